@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result, WrapErr, bail, miette};
+use serde::Deserialize;
+
+use crate::client::ExecResult;
+use crate::config::{AppSpec, Arch, ScoopApp, ScoopBucket};
+use crate::{InstalledApp, InstalledThings};
+
+/// 新しいバージョンが利用可能なアプリケーション。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutdatedApp {
+    pub app: ScoopApp,
+    pub installed_version: String,
+    pub latest_version: String,
+}
+
+/// パッケージマネージャーのバックエンド。
+///
+/// 実際に scoop を呼び出す処理はこのトレイトの裏に隠れているため、依存関係の解決や差分計算のロジ
+/// ックは具体的な実装 (PowerShell 経由の [`ScoopClient`](crate::client::ScoopClient) など) を知ら
+/// ずに書ける。`exec` と `open_session` 以外のメソッドには scoop コマンドの出力をパースする既定実
+/// 装があるので、scoop を実際に実行する実装はこの二つだけを実装すればよい。
+pub trait Backend: Send {
+    /// scoop のサブコマンドを実行します。
+    fn exec(&mut self, commands: &[&str]) -> Result<ExecResult>;
+
+    /// 同じ環境を操作する新しいセッションを開きます。依存関係の解決を並行して行うために使う。
+    fn open_session(&self) -> Result<Box<dyn Backend>>;
+
+    /// インストールされているバケットとアプリケーションを取得します。
+    fn list_installed(&mut self) -> Result<InstalledThings> {
+        let exported = self
+            .exec(&["export"])
+            .wrap_err("failed to invoke `scoop export`")?;
+        if !exported.status.success() {
+            bail!("failed to export scoop status: {}", exported.stderr);
+        }
+
+        #[derive(Deserialize)]
+        struct ExportedScoopData {
+            buckets: Vec<ExportedScoopBucket>,
+            apps: Vec<ExportedScoopApp>,
+        }
+
+        #[derive(Deserialize)]
+        struct ExportedScoopBucket {
+            #[serde(rename = "Name")]
+            name: String,
+            #[serde(rename = "Source")]
+            source: String,
+        }
+
+        #[derive(Deserialize)]
+        struct ExportedScoopApp {
+            #[serde(rename = "Name")]
+            name: String,
+            #[serde(rename = "Source")]
+            bucket: Option<String>,
+            #[serde(rename = "Version")]
+            version: String,
+            /// "Global install, Held package, 32bit" のようなカンマ区切りの付加情報。アーキテクチャ
+            /// は既定のものと異なる場合だけ出力される。
+            #[serde(rename = "Info", default)]
+            info: Option<String>,
+        }
+
+        let data: ExportedScoopData = serde_json::from_str(&exported.stdout)
+            .into_diagnostic()
+            .wrap_err("failed to parse `scoop export` output")?;
+
+        let mut scoop_apps = HashMap::new();
+        let mut global_scoop_apps = HashMap::new();
+        for app in &data.apps {
+            let Some(bucket) = &app.bucket else {
+                continue;
+            };
+            let scoop_app = ScoopApp {
+                name: app.name.clone(),
+                bucket_name: bucket.clone(),
+            };
+            let info = app
+                .info
+                .iter()
+                .flat_map(|info| info.split(','))
+                .map(str::trim)
+                .collect::<Vec<_>>();
+            let arch = info
+                .iter()
+                .find_map(|item| Arch::parse(item))
+                .unwrap_or_else(Arch::host);
+            let installed = InstalledApp {
+                version: app.version.clone(),
+                arch,
+                held: info.contains(&"Held package"),
+            };
+            if info.contains(&"Global install") {
+                global_scoop_apps.insert(scoop_app, installed);
+            } else {
+                scoop_apps.insert(scoop_app, installed);
+            }
+        }
+
+        Ok(InstalledThings {
+            scoop_buckets: data
+                .buckets
+                .iter()
+                .map(|bucket| ScoopBucket {
+                    name: bucket.name.clone(),
+                    source: bucket.source.clone(),
+                })
+                .collect(),
+            scoop_apps,
+            global_scoop_apps,
+        })
+    }
+
+    /// `scoop depends` と同様に、アプリケーション自身と、間接的なものも含むすべての依存関係を取得
+    /// します。
+    fn dependencies_of(&mut self, app: &ScoopApp) -> Result<HashSet<ScoopApp>> {
+        let ExecResult {
+            stdout,
+            stderr,
+            status,
+        } = self.exec(&["depends", &app.to_string()])?;
+
+        if !status.success() || stdout.contains("Couldn't find manifest for") {
+            bail!("failed to get dependencies for {app}: {stderr}",);
+        }
+
+        stdout
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .skip(1) // ヘッダー行: Source Name
+            .skip(1) // ヘッダー行: ------ ----
+            .map(|line| {
+                let [bucket_name, name] = line
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .map_err(|_| miette!("invalid dependency line: {line}"))?;
+
+                Ok(ScoopApp {
+                    bucket_name: bucket_name.to_string(),
+                    name: name.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// `dependencies_of` の結果を、ローカルのバケットの状態をキーにしてキャッシュしてよいかどうか
+    /// を返します。
+    fn caches_dependencies(&self) -> bool {
+        true
+    }
+
+    /// 新しいバージョンが利用可能なアプリケーションを取得します。
+    fn outdated_apps(&mut self) -> Result<Vec<OutdatedApp>> {
+        let ExecResult {
+            stdout,
+            stderr,
+            status,
+        } = self
+            .exec(&["status"])
+            .wrap_err("failed to invoke `scoop status`")?;
+        if !status.success() {
+            bail!("failed to get scoop status: {stderr}");
+        }
+
+        // 表の前にはバケットの更新状況などのメッセージが出力されるので、ヘッダー行を探してそれ以降
+        // を読む
+        let mut lines = stdout
+            .lines()
+            .skip_while(|line| !(line.starts_with("Name") && line.contains("Latest Version")))
+            .filter(|line| !line.trim().is_empty());
+        let Some(header) = lines.next() else {
+            // 表がない場合はすべて最新
+            return Ok(Vec::new());
+        };
+
+        // インストールに失敗したアプリケーションなども同じ表に出力され、その場合は空の列がある。空白
+        // で区切ると列がずれるので、ヘッダー行の位置で切り出す。
+        let column_start = |title: &str| {
+            header
+                .find(title)
+                .ok_or_else(|| miette!("invalid status header: {header}"))
+        };
+        let installed_start = column_start("Installed Version")?;
+        let latest_start = column_start("Latest Version")?;
+        let latest_end = header[latest_start + "Latest Version".len()..]
+            .find(|c: char| !c.is_whitespace())
+            .map_or(usize::MAX, |pos| {
+                latest_start + "Latest Version".len() + pos
+            });
+        let column = |line: &str, start: usize, end: usize| {
+            let end = end.min(line.len());
+            line.get(start.min(end)..end)
+                .unwrap_or("")
+                .trim()
+                .to_string()
+        };
+
+        let rows = lines
+            .skip(1) // ヘッダー行: ---- ----------------- ...
+            .map(|line| {
+                (
+                    column(line, 0, installed_start),
+                    column(line, installed_start, latest_start),
+                    column(line, latest_start, latest_end),
+                )
+            })
+            .filter(|(_, _, latest_version)| !latest_version.is_empty())
+            .collect_vec();
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // scoop status はバケット名を出力しないので、インストール済みのアプリケーションと名前とバー
+        // ジョンで突き合わせてバケットを特定する。別のバケットの同じ名前のアプリケーションを取り違え
+        // ないため。
+        let installed = self.list_installed()?;
+        Ok(rows
+            .into_iter()
+            .flat_map(|(name, installed_version, latest_version)| {
+                installed
+                    .apps()
+                    .filter(|(app, _, installed)| {
+                        app.name == name && installed.version == installed_version
+                    })
+                    .map(|(app, _, _)| OutdatedApp {
+                        app: app.clone(),
+                        installed_version: installed_version.clone(),
+                        latest_version: latest_version.clone(),
+                    })
+                    .collect_vec()
+            })
+            .unique()
+            .collect())
+    }
+
+    /// アプリケーションをまとめてインストールします。バージョンやアーキテクチャが指定されていれば
+    /// それに従います。
+    fn install_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        // --arch や --global はコマンド全体に効くので、同じオプションのものごとにまとめて実行する
+        let mut groups: Vec<(_, Vec<&AppSpec>)> = Vec::new();
+        for spec in apps {
+            let key = (spec.arch, spec.global);
+            match groups.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, group)) => group.push(spec),
+                None => groups.push((key, vec![spec])),
+            }
+        }
+
+        for ((arch, global), group) in groups {
+            let mut args = vec!["install"];
+            if let Some(arch) = arch {
+                args.extend(["--arch", arch.as_str()]);
+            }
+            if global {
+                args.push("--global");
+            }
+            let app_ids = group.iter().map(|app| app.to_string()).collect::<Vec<_>>();
+            args.extend(app_ids.iter().map(|id| id.as_str()));
+            let output = self
+                .exec(&args)
+                .wrap_err("failed to install applications")?;
+            if !output.status.success() {
+                bail!("failed to install applications: {}", output.stderr.trim());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// アプリケーションを現在のバージョンで固定します。
+    fn hold_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        for global in [false, true] {
+            let app_names = apps
+                .iter()
+                .filter(|spec| spec.global == global)
+                .map(|spec| &*spec.app.name)
+                .collect::<Vec<_>>();
+            if app_names.is_empty() {
+                continue;
+            }
+
+            let mut args = vec!["hold"];
+            if global {
+                args.push("--global");
+            }
+            args.extend(app_names);
+            let output = self.exec(&args).wrap_err("failed to hold applications")?;
+            if !output.status.success() {
+                bail!("failed to hold applications: {}", output.stderr.trim());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// アプリケーションの固定を解除します。
+    fn unhold_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        for global in [false, true] {
+            let app_names = apps
+                .iter()
+                .filter(|spec| spec.global == global)
+                .map(|spec| &*spec.app.name)
+                .collect::<Vec<_>>();
+            if app_names.is_empty() {
+                continue;
+            }
+
+            let mut args = vec!["unhold"];
+            if global {
+                args.push("--global");
+            }
+            args.extend(app_names);
+            let output = self.exec(&args).wrap_err("failed to unhold applications")?;
+            if !output.status.success() {
+                bail!("failed to unhold applications: {}", output.stderr.trim());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// アプリケーションをまとめてアンインストールします。`global` 以外の属性は使いません。
+    fn uninstall_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        for global in [false, true] {
+            let app_ids = apps
+                .iter()
+                .filter(|spec| spec.global == global)
+                .map(|spec| spec.app.to_string())
+                .collect::<Vec<_>>();
+            if app_ids.is_empty() {
+                continue;
+            }
+
+            let mut args = vec!["uninstall"];
+            if global {
+                args.push("--global");
+            }
+            args.extend(app_ids.iter().map(|id| id.as_str()));
+            let output = self
+                .exec(&args)
+                .wrap_err("failed to uninstall applications")?;
+            if !output.status.success() {
+                bail!("failed to uninstall applications: {}", output.stderr.trim());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// アプリケーションをまとめて最新のバージョンに更新します。`global` 以外の属性は使いません。
+    fn update_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        for global in [false, true] {
+            let app_names = apps
+                .iter()
+                .filter(|spec| spec.global == global)
+                .map(|spec| &*spec.app.name)
+                .collect::<Vec<_>>();
+            if app_names.is_empty() {
+                continue;
+            }
+
+            let mut args = vec!["update"];
+            if global {
+                args.push("--global");
+            }
+            args.extend(app_names);
+            let output = self.exec(&args).wrap_err("failed to update applications")?;
+            if !output.status.success() {
+                bail!("failed to update applications: {}", output.stderr.trim());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// バケットを追加します。
+    fn add_bucket(&mut self, bucket: &ScoopBucket) -> Result<()> {
+        let output = self
+            .exec(&["bucket", "add", &bucket.name, &bucket.source])
+            .wrap_err_with(|| {
+                miette!(
+                    "failed to install bucket {} from {}",
+                    bucket.name,
+                    bucket.source
+                )
+            })?;
+        if !output.status.success() {
+            bail!(
+                "failed to install bucket {}: {}",
+                bucket.name,
+                output.stderr.trim()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// バケットをまとめて削除します。
+    fn remove_buckets(&mut self, buckets: &[ScoopBucket]) -> Result<()> {
+        let mut args = vec!["bucket", "rm"];
+        args.extend(buckets.iter().map(|b| &*b.name));
+        let output = self.exec(&args).wrap_err("failed to uninstall buckets")?;
+        if !output.status.success() {
+            bail!("failed to uninstall buckets: {}", output.stderr.trim());
+        }
+
+        Ok(())
+    }
+
+    /// コマンドの実行を終えたときに、成否にかかわらず呼び出されます。既定では何もしない。
+    fn finish(&self) {}
+}
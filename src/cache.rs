@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result, WrapErr, miette};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::Resolver;
+use crate::config::{Arch, ScoopApp};
+use crate::manifest::buckets_dir;
+
+/// 依存関係のキャッシュを置くディレクトリを指定する環境変数。
+pub const CACHE_DIR_ENV: &str = "DECLARATIVE_SCOOP_CACHE_DIR";
+
+/// 解決済みの依存関係 (アプリケーション → `scoop depends` の結果) をディスクに保存するキャッシュ。
+///
+/// マニフェストが変わらない限り依存関係も変わらないので、ローカルのバケットの状態 (git のコミッ
+/// トとマニフェストの更新日時、git でなければマニフェストのハッシュ) をキーにして保存しておく。
+/// `scoop update` などでいずれかのバケットが更新されるとキーが変わり、キャッシュ全体が無効にな
+/// る。依存関係は別のバケットのアプリケーションにまたがることがあるため、バケットごとではなく全
+/// 体をまとめて無効にする。
+pub struct DependencyCache {
+    path: PathBuf,
+    key: CacheKey,
+    dependencies: HashMap<ScoopApp, HashSet<ScoopApp>>,
+    /// 読み込んだ後に新しく追加された項目があるかどうか。
+    dirty: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheKey {
+    resolver: Resolver,
+    arch: Arch,
+    /// バケット名とその状態。
+    buckets: BTreeMap<String, String>,
+}
+
+/// ディスク上のキャッシュファイルの形式。
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    key: CacheKey,
+    dependencies: BTreeMap<String, Vec<ScoopApp>>,
+}
+
+impl DependencyCache {
+    /// 現在のバケットの状態に対応するキャッシュを開きます。scoop のバケットのディレクトリが見つ
+    /// からなければキャッシュは使えないので `None` を返します。
+    pub fn open(resolver: Resolver, arch: Arch) -> Result<Option<Self>> {
+        let Ok(buckets_dir) = buckets_dir() else {
+            return Ok(None);
+        };
+        let path = cache_dir()?.join("dependencies.json");
+
+        Self::open_at(&buckets_dir, path, resolver, arch).map(Some)
+    }
+
+    /// `buckets_dir` のバケットの状態に対応する、`path` に保存されたキャッシュを開きます。
+    fn open_at(buckets_dir: &Path, path: PathBuf, resolver: Resolver, arch: Arch) -> Result<Self> {
+        let key = CacheKey {
+            resolver,
+            arch,
+            buckets: bucket_states(buckets_dir)?,
+        };
+
+        // 壊れていたり古い形式だったりするファイルは、キーが異なる場合と同様に捨てて作り直す
+        let dependencies = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<CacheFile>(&text).ok())
+            .filter(|file| file.key == key)
+            .map(|file| {
+                file.dependencies
+                    .into_iter()
+                    .filter_map(|(app, deps)| Some((app.parse().ok()?, deps.into_iter().collect())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            path,
+            key,
+            dependencies,
+            dirty: false,
+        })
+    }
+
+    pub fn get(&self, app: &ScoopApp) -> Option<&HashSet<ScoopApp>> {
+        self.dependencies.get(app)
+    }
+
+    pub fn insert(&mut self, app: ScoopApp, dependencies: HashSet<ScoopApp>) {
+        if self.dependencies.get(&app) != Some(&dependencies) {
+            self.dependencies.insert(app, dependencies);
+            self.dirty = true;
+        }
+    }
+
+    /// 新しく追加された項目があれば、キャッシュをファイルに書き出します。
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let file = CacheFile {
+            key: self.key.clone(),
+            dependencies: self
+                .dependencies
+                .iter()
+                .map(|(app, deps)| {
+                    let deps = deps.iter().cloned().sorted_by_key(|dep| dep.to_string());
+                    (app.to_string(), deps.collect())
+                })
+                .collect(),
+        };
+        let json = serde_json::to_string_pretty(&file)
+            .into_diagnostic()
+            .wrap_err("failed to serialize dependency cache")?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to create directory {}", dir.display()))?;
+        }
+
+        // 同時に実行された別のプロセスが書きかけのファイルを読まないよう、同じディレクトリの一時
+        // ファイルに書いてから置き換える
+        let temp = self
+            .path
+            .with_extension(format!("json.{}.tmp", process::id()));
+        fs::write(&temp, json)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to write {}", temp.display()))?;
+        fs::rename(&temp, &self.path)
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&temp);
+            })
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to replace {}", self.path.display()))
+    }
+}
+
+/// キャッシュを置くディレクトリを返します。
+fn cache_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os(CACHE_DIR_ENV) {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let base = env::var_os("LOCALAPPDATA")
+        .or_else(|| env::var_os("XDG_CACHE_HOME"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .ok_or_else(|| miette!("failed to find a directory for the dependency cache"))?;
+
+    Ok(base.join("declarative-scoop"))
+}
+
+/// 各バケットの状態を返します。
+fn bucket_states(buckets_dir: &Path) -> Result<BTreeMap<String, String>> {
+    let entries = fs::read_dir(buckets_dir)
+        .into_diagnostic()
+        .wrap_err("failed to list buckets")?;
+
+    let mut states = BTreeMap::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+
+        // コミットされていないマニフェストの変更も反映されるよう、git のバケットでも更新日時は見る
+        let state = match git_revision(&path) {
+            Some(commit) => format!("{commit}:{}", manifest_mtimes(&path)?),
+            None => manifest_hash(&path)?,
+        };
+        states.insert(entry.file_name().to_string_lossy().into_owned(), state);
+    }
+
+    Ok(states)
+}
+
+/// バケットが git リポジトリであれば、チェックアウトされているコミットを返します。
+// git コマンドを起動するとそれだけで時間がかかるので、.git ディレクトリを直接読む
+fn git_revision(bucket_dir: &Path) -> Option<String> {
+    let git_dir = bucket_dir.join(".git");
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let Some(reference) = head.trim().strip_prefix("ref: ") else {
+        // detached HEAD
+        return Some(head.trim().to_string());
+    };
+
+    if let Ok(commit) = fs::read_to_string(git_dir.join(reference)) {
+        return Some(commit.trim().to_string());
+    }
+
+    // git gc の後などは refs/heads 以下ではなく packed-refs に書かれている
+    fs::read_to_string(git_dir.join("packed-refs"))
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let (commit, name) = line.split_once(' ')?;
+            (name == reference).then(|| commit.to_string())
+        })
+}
+
+/// バケットにあるマニフェストのパスを名前順に返します。
+fn manifest_paths(bucket_dir: &Path) -> Result<Vec<PathBuf>> {
+    let manifest_dir = Some(bucket_dir.join("bucket"))
+        .filter(|dir| dir.is_dir())
+        .unwrap_or_else(|| bucket_dir.to_path_buf());
+    let manifests = fs::read_dir(&manifest_dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to list manifests in {}", manifest_dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .sorted()
+        .collect();
+
+    Ok(manifests)
+}
+
+/// git で管理されているバケットの作業ツリーの状態として、マニフェストの更新日時とサイズのハッシュ
+/// を返します。内容をすべて読むよりずっと速い。
+fn manifest_mtimes(bucket_dir: &Path) -> Result<String> {
+    let mut hasher = DefaultHasher::new();
+    for path in manifest_paths(bucket_dir)? {
+        let metadata = fs::metadata(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to read metadata of {}", path.display()))?;
+        path.file_name().hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        metadata.modified().ok().hash(&mut hasher);
+    }
+
+    Ok(format!("mtimes:{:016x}", hasher.finish()))
+}
+
+/// git で管理されていないバケットの状態として、マニフェストの内容のハッシュを返します。
+// DefaultHasher のアルゴリズムは Rust のバージョンによって変わりうるが、その場合はキャッシュが
+// 無効になるだけなので問題ない
+fn manifest_hash(bucket_dir: &Path) -> Result<String> {
+    let mut hasher = DefaultHasher::new();
+    for path in manifest_paths(bucket_dir)? {
+        let content = fs::read(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to read manifest {}", path.display()))?;
+        path.file_name().hash(&mut hasher);
+        content.hash(&mut hasher);
+    }
+
+    Ok(format!("manifests:{:016x}", hasher.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, app, write};
+
+    /// scoop のバケットのディレクトリ。
+    fn buckets_dir(dir: &TempDir) -> PathBuf {
+        dir.path().join("buckets")
+    }
+
+    /// git のバケット main と git でないバケット local を持つ scoop のディレクトリを作ります。
+    fn buckets() -> TempDir {
+        let dir = TempDir::new();
+        let buckets_dir = self::buckets_dir(&dir);
+        write(
+            &buckets_dir.join("main/.git/HEAD"),
+            "ref: refs/heads/master\n",
+        );
+        write(&buckets_dir.join("main/.git/refs/heads/master"), "aaaa\n");
+        write(&buckets_dir.join("main/bucket/git.json"), "{}");
+        write(&buckets_dir.join("local/bucket/tool.json"), "{}");
+        dir
+    }
+
+    fn open(buckets_dir: &Path, arch: Arch) -> DependencyCache {
+        // キャッシュファイルがバケットとみなされないよう、バケットのディレクトリの外に置く
+        let path = buckets_dir
+            .with_file_name("cache")
+            .join("dependencies.json");
+        DependencyCache::open_at(buckets_dir, path, Resolver::Scoop, arch).unwrap()
+    }
+
+    /// キャッシュに一つ項目を追加して保存します。
+    fn populate(buckets_dir: &Path) {
+        let mut cache = open(buckets_dir, Arch::X64);
+        cache.insert(app("main/git"), HashSet::from([app("main/git")]));
+        cache.save().unwrap();
+    }
+
+    #[test]
+    fn reuses_entries_with_matching_key() {
+        let dir = buckets();
+        let buckets_dir = self::buckets_dir(&dir);
+        populate(&buckets_dir);
+        // 書き出しに使った一時ファイルは残らない
+        let files = fs::read_dir(buckets_dir.with_file_name("cache"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect_vec();
+        assert_eq!(files, ["dependencies.json"]);
+
+        let cache = open(&buckets_dir, Arch::X64);
+        assert_eq!(
+            cache.get(&app("main/git")),
+            Some(&HashSet::from([app("main/git")]))
+        );
+        // アーキテクチャが違えば別のキャッシュになる
+        assert_eq!(open(&buckets_dir, Arch::X86).get(&app("main/git")), None);
+    }
+
+    #[test]
+    fn invalidates_on_bucket_change() {
+        let dir = buckets();
+        let buckets_dir = self::buckets_dir(&dir);
+
+        // git のバケットのコミットが変わった
+        populate(&buckets_dir);
+        write(&buckets_dir.join("main/.git/refs/heads/master"), "bbbb\n");
+        assert_eq!(open(&buckets_dir, Arch::X64).get(&app("main/git")), None);
+
+        // git のバケットのマニフェストがコミットせずに編集された
+        populate(&buckets_dir);
+        write(
+            &buckets_dir.join("main/bucket/git.json"),
+            r#"{ "depends": "tool" }"#,
+        );
+        assert_eq!(open(&buckets_dir, Arch::X64).get(&app("main/git")), None);
+
+        // git でないバケットのマニフェストが変わった
+        populate(&buckets_dir);
+        write(
+            &buckets_dir.join("local/bucket/tool.json"),
+            r#"{ "version": "2" }"#,
+        );
+        assert_eq!(open(&buckets_dir, Arch::X64).get(&app("main/git")), None);
+
+        // バケットが追加された
+        populate(&buckets_dir);
+        write(&buckets_dir.join("extras/bucket/other.json"), "{}");
+        assert_eq!(open(&buckets_dir, Arch::X64).get(&app("main/git")), None);
+    }
+
+    #[test]
+    fn reads_revision_from_packed_refs() {
+        let dir = TempDir::new();
+        let bucket_dir = buckets_dir(&dir).join("main");
+        write(&bucket_dir.join(".git/HEAD"), "ref: refs/heads/master\n");
+        write(
+            &bucket_dir.join(".git/packed-refs"),
+            "# pack-refs with: peeled fully-peeled sorted\n\
+             cccc refs/heads/develop\n\
+             dddd refs/heads/master\n",
+        );
+        assert_eq!(git_revision(&bucket_dir), Some("dddd".to_string()));
+
+        write(&bucket_dir.join(".git/HEAD"), "eeee\n");
+        assert_eq!(git_revision(&bucket_dir), Some("eeee".to_string()));
+    }
+}
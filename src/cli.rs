@@ -0,0 +1,163 @@
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::thread;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ResolutionFailurePolicy;
+
+// ここに書いたドキュメントコメントはそのまま --help の出力になるため、他のメッセージと同様に英語で
+// 書く。
+
+/// Declaratively manage Scoop buckets and applications.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the configuration file
+    #[arg(short, long, global = true, default_value = "app-requirements.yaml")]
+    pub config: PathBuf,
+
+    /// Profile that enables matching conditional sections of the configuration (repeatable)
+    #[arg(short, long = "profile", global = true)]
+    pub profiles: Vec<String>,
+
+    #[command(flatten)]
+    pub resolve: ResolveOptions,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Show the changes required to match the configuration
+    Plan {
+        /// Output format of the plan
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        /// Also save the plan to a file so it can be applied later with `apply-plan`
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        #[command(flatten)]
+        options: PlanOptions,
+    },
+    /// Apply the changes after confirmation (default)
+    Apply {
+        /// Apply without asking for confirmation
+        #[arg(short, long, conflicts_with = "dry_run")]
+        yes: bool,
+        /// Only show the changes; exit with code 2 if there are pending changes
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        options: PlanOptions,
+    },
+    /// Apply a plan saved by `plan --out` exactly as it was reviewed
+    ApplyPlan {
+        /// Plan file to apply
+        plan: PathBuf,
+        /// Apply without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Show whether each configured item is installed
+    Status,
+    /// Check that the configuration file is valid
+    Validate,
+    /// Explain why an application is required
+    Why {
+        /// Application to explain, as `bucket/name` or just `name`
+        app: String,
+        #[command(flatten)]
+        options: PlanOptions,
+    },
+    /// Render the resolved dependency graph, colored by the status of each app
+    Graph {
+        /// Output format of the graph
+        #[arg(short, long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+        /// File to write to (defaults to standard output)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write the currently installed items in the configuration format
+    Export {
+        /// File to write to (defaults to standard output)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// 実行計画の計算方法に関するオプション。
+#[derive(Debug, Clone, Default, Args)]
+pub struct PlanOptions {
+    /// Upgrade outdated applications even if not enabled in the configuration
+    #[arg(long)]
+    pub update: bool,
+    /// Only install missing items; never uninstall items not in the configuration
+    #[arg(long)]
+    pub additive: bool,
+    /// How to handle apps whose dependencies cannot be resolved [default: keep]
+    #[arg(long, value_enum)]
+    pub on_resolution_failure: Option<ResolutionFailurePolicy>,
+}
+
+/// 並列数を指定しなかったときの上限。scoop のセッションはそれぞれ PowerShell のプロセスなので、
+/// CPU が多くても増やしすぎない。
+const MAX_DEFAULT_JOBS: NonZeroUsize = NonZeroUsize::new(4).unwrap();
+
+/// 依存関係の解決方法に関するオプション。
+#[derive(Debug, Clone, Args)]
+pub struct ResolveOptions {
+    /// Number of scoop sessions used to resolve dependencies in parallel [default: number of CPUs,
+    /// at most 4]
+    #[arg(short, long, global = true)]
+    pub jobs: Option<NonZeroUsize>,
+    /// How to look up the dependencies of applications
+    #[arg(long, global = true, value_enum, default_value_t = Resolver::Scoop)]
+    pub resolver: Resolver,
+    /// Resolve all dependencies again instead of using the cached results
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+}
+
+impl ResolveOptions {
+    /// 依存関係の解決に使うセッションの数を返します。
+    pub fn jobs(&self) -> NonZeroUsize {
+        self.jobs.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .unwrap_or(NonZeroUsize::MIN)
+                .min(MAX_DEFAULT_JOBS)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resolver {
+    /// Run `scoop depends` for each application
+    Scoop,
+    /// Read the manifests directly from the local bucket directories (much faster)
+    Native,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable colored text
+    Text,
+    /// JSON for other tools
+    Json,
+    /// YAML for other tools
+    Yaml,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// Mermaid flowchart
+    Mermaid,
+    /// JSON for other tools
+    Json,
+}
@@ -1,17 +1,16 @@
 use itertools::Itertools;
 use miette::{Context, IntoDiagnostic, Result, bail};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
 use std::thread;
-use std::{
-    env,
-    io::{BufRead, BufReader, Read, Write},
-};
 
 #[cfg(unix)]
 use std::os::unix::process::ExitStatusExt;
 #[cfg(windows)]
 use std::os::windows::process::ExitStatusExt;
 
+use crate::backend::Backend;
+
 #[derive(Debug)]
 pub struct ExecResult {
     pub stdout: String,
@@ -48,13 +47,23 @@ impl ScoopClient {
             script_path,
         })
     }
+}
 
-    pub fn exec(&mut self, commands: &[&str]) -> Result<ExecResult> {
+/// PowerShell 上で実際に scoop を実行するバックエンド。
+impl Backend for ScoopClient {
+    fn exec(&mut self, commands: &[&str]) -> Result<ExecResult> {
         let mut full_command = vec!["&", self.script_path.as_str()];
         full_command.extend_from_slice(commands);
 
         self.powershell.exec(&full_command)
     }
+
+    fn open_session(&self) -> Result<Box<dyn Backend>> {
+        Ok(Box::new(Self {
+            powershell: PowerShellClient::new_quiet()?,
+            script_path: self.script_path.clone(),
+        }))
+    }
 }
 
 /// PowerShell プロセスを保持し、コマンド実行を仲介するクライアント。
@@ -65,6 +74,8 @@ impl ScoopClient {
 pub struct PowerShellClient {
     process: Child,
     stdin: ChildStdin,
+    /// 終了時にメッセージを表示しないかどうか。
+    quiet: bool,
 }
 
 impl PowerShellClient {
@@ -86,7 +97,11 @@ impl PowerShellClient {
             .wrap_err("failed to spawn powershell process")?;
 
         let stdin = process.stdin.take().expect("Failed to open stdin");
-        let mut client = Self { process, stdin };
+        let mut client = Self {
+            process,
+            stdin,
+            quiet: false,
+        };
 
         // PowerShell の出力エンコーディングをUTF-8に設定
         client
@@ -98,6 +113,14 @@ impl PowerShellClient {
         Ok(client)
     }
 
+    /// 終了時にメッセージを表示しない PowerShell クライアントを作成します。並列処理のために一時的に
+    /// 開くセッション用。
+    pub fn new_quiet() -> Result<Self> {
+        let mut client = Self::new()?;
+        client.quiet = true;
+        Ok(client)
+    }
+
     /// 起動中のPowerShellプロセス上でコマンドを実行します。
     pub fn exec(&mut self, command_and_args: &[&str]) -> Result<ExecResult> {
         // 実行するコマンドをスペースで連結
@@ -192,6 +215,8 @@ impl Drop for PowerShellClient {
         let _ = self.stdin.flush();
         // プロセスの終了を待つ
         let _ = self.process.wait();
-        println!("\nPowerShell process terminated.");
+        if !self.quiet {
+            eprintln!("\nPowerShell process terminated.");
+        }
     }
 }
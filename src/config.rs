@@ -0,0 +1,1517 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt, fs, mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use clap::ValueEnum;
+use globset::{GlobBuilder, GlobMatcher};
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result, WrapErr, bail, miette};
+use serde::{Deserialize, Serialize};
+
+use crate::diagnostic::{ConfigDiagnostic, ConfigProblems, ConfigSource, Segment};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// 一緒に読み込む設定ファイル。相対パスと glob パターンが使える。チームで共有する基本の設定に
+    /// 役割や個人ごとの設定を重ねるため。
+    ///
+    /// ```yaml
+    /// include:
+    ///   - base.yaml
+    ///   - roles/*.yaml
+    /// ```
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub scoop_buckets: Vec<ScoopBucket>,
+    #[serde(default)]
+    pub scoop_apps: Vec<AppSpec>,
+    /// アーキテクチャを指定していないアプリケーション (依存関係も含む) のアーキテクチャ。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_arch: Option<Arch>,
+    /// 依存関係の解決に失敗したアプリケーションの扱い。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_resolution_failure: Option<ResolutionFailurePolicy>,
+    #[serde(default, skip_serializing_if = "UpdatePolicy::is_default")]
+    pub update: UpdatePolicy,
+    #[serde(default, skip_serializing_if = "IgnoreRules::is_empty")]
+    pub ignore: IgnoreRules,
+    /// 不足しているものをインストールするだけで、設定ファイルに記載されていないものをアンインスト
+    /// ールしないかどうか。チームで共有する設定で、各自が入れたツールを消さないようにするため。
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub additive: bool,
+    /// この設定を適用する条件。条件に一致しなければ、取り込むファイルやセクションも含めて何も適用
+    /// しない。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<Condition>,
+    /// 条件付きで適用される設定。一つのリポジトリでデスクトップ、ノート PC、ビルド用の VM などの
+    /// 設定をまとめて管理するため。ファイルの本体に続けてまとめる。
+    ///
+    /// ```yaml
+    /// sections:
+    ///   - when: { hostname: "BUILD-*" }
+    ///     scoop_apps: [main/7zip]
+    ///   - when: { profile: laptop, env: { CI: "*" } }
+    ///     include: [laptop.yaml]
+    /// ```
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sections: Vec<Config>,
+}
+
+/// 依存関係の解決に失敗したアプリケーションの扱い。
+///
+/// 一時的なネットワークの問題などで解決に失敗しただけのアプリケーションを、必要ないものとしてアン
+/// インストールしてしまわないよう、既定ではインストール済みのものを残す。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResolutionFailurePolicy {
+    /// 計画を作成せずに終了する。
+    #[value(help = "Stop without making any changes")]
+    Abort,
+    /// 解決できなかったアプリケーションをインストールしないが、インストール済みであれば、その依存
+    /// 関係かもしれないものも含めて残す。
+    #[default]
+    #[value(help = "Skip the app but never uninstall it or anything it may depend on")]
+    Keep,
+    /// 解決できなかったアプリケーションを必要ないものとして扱う。アンインストールされることがある。
+    #[value(help = "Skip the app and treat it as not required, which may uninstall it")]
+    Warn,
+}
+
+impl fmt::Display for ResolutionFailurePolicy {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ResolutionFailurePolicy::Abort => "abort",
+            ResolutionFailurePolicy::Keep => "keep",
+            ResolutionFailurePolicy::Warn => "warn",
+        };
+        write!(b, "{name}")
+    }
+}
+
+/// 設定やセクションを適用する条件。指定した項目がすべて一致したときに満たされる。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Condition {
+    /// ホスト名のパターン。Windows と同様に、大文字と小文字は区別しない。
+    #[serde(deserialize_with = "deserialize_case_insensitive")]
+    pub hostname: Option<GlobPattern>,
+    /// ユーザー名のパターン。Windows と同様に、大文字と小文字は区別しない。
+    #[serde(deserialize_with = "deserialize_case_insensitive")]
+    pub username: Option<GlobPattern>,
+    /// コマンドラインの `--profile` で選択されたプロファイル名。
+    pub profile: Option<String>,
+    /// 環境変数名と値のパターン。環境変数が設定されていなければ一致しない。
+    pub env: BTreeMap<String, GlobPattern>,
+}
+
+impl Condition {
+    fn matches(&self, context: &ConfigContext) -> bool {
+        let matches_value = |pattern: &Option<GlobPattern>, value: &Option<String>| {
+            pattern.as_ref().is_none_or(|pattern| {
+                value
+                    .as_deref()
+                    .is_some_and(|value| pattern.is_match(value))
+            })
+        };
+
+        matches_value(&self.hostname, &context.hostname)
+            && matches_value(&self.username, &context.username)
+            && self
+                .profile
+                .as_ref()
+                .is_none_or(|profile| context.profiles.contains(profile))
+            && self
+                .env
+                .iter()
+                .all(|(name, pattern)| env::var(name).is_ok_and(|value| pattern.is_match(&value)))
+    }
+}
+
+/// 条件付きのセクションを評価するための、実行中の環境の情報。
+#[derive(Debug, Clone, Default)]
+pub struct ConfigContext {
+    pub hostname: Option<String>,
+    pub username: Option<String>,
+    /// コマンドラインで選択されたプロファイル。
+    pub profiles: Vec<String>,
+}
+
+impl ConfigContext {
+    /// 現在のホスト名とユーザー名で環境の情報を作成します。
+    pub fn current(profiles: Vec<String>) -> Self {
+        Self {
+            hostname: whoami::hostname().ok(),
+            username: whoami::username().ok(),
+            profiles,
+        }
+    }
+}
+
+/// 古くなったアプリケーションを更新するかどうかの設定。
+///
+/// ```yaml
+/// update:
+///   enabled: true
+///   apps:
+///     main/nodejs: false
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdatePolicy {
+    /// すべてのアプリケーションを更新するかどうか。
+    pub enabled: bool,
+    /// アプリケーションごとの設定。`enabled` より優先される。
+    pub apps: HashMap<ScoopApp, bool>,
+}
+
+impl UpdatePolicy {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// アプリケーションを更新するべきかを返します。`force` はコマンドラインで更新が指示されたか
+    /// どうか。
+    pub fn should_update(&self, app: &ScoopApp, force: bool) -> bool {
+        self.apps.get(app).copied().unwrap_or(self.enabled || force)
+    }
+}
+
+/// 設定ファイルに記載されていなくてもアンインストールしないアプリケーションとバケット。一時的に
+/// 入れたツールなどを守るため。
+///
+/// ```yaml
+/// ignore:
+///   scoop_apps: [extras/*, "*/vscode"]
+///   scoop_buckets: [private-*]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IgnoreRules {
+    /// `bucket/name` に対するパターン。
+    pub scoop_apps: Vec<GlobPattern>,
+    /// バケット名に対するパターン。
+    pub scoop_buckets: Vec<GlobPattern>,
+}
+
+impl IgnoreRules {
+    fn is_empty(&self) -> bool {
+        self.scoop_apps.is_empty() && self.scoop_buckets.is_empty()
+    }
+
+    pub fn ignores_app(&self, app: &ScoopApp) -> bool {
+        let app = app.to_string();
+        self.scoop_apps.iter().any(|pattern| pattern.is_match(&app))
+    }
+
+    pub fn ignores_bucket(&self, name: &str) -> bool {
+        self.scoop_buckets
+            .iter()
+            .any(|pattern| pattern.is_match(name))
+    }
+}
+
+/// glob パターン。設定ファイルの読み込み時にコンパイルしておく。
+#[derive(Debug, Clone)]
+pub struct GlobPattern(GlobMatcher);
+
+impl GlobPattern {
+    fn new(pattern: &str, case_insensitive: bool) -> Result<Self, String> {
+        GlobBuilder::new(pattern)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map(|glob| GlobPattern(glob.compile_matcher()))
+            .map_err(|e| format!("invalid pattern `{pattern}`: {e}"))
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        self.0.is_match(s)
+    }
+}
+
+impl PartialEq for GlobPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.glob() == other.0.glob()
+    }
+}
+
+impl Eq for GlobPattern {}
+
+impl fmt::Display for GlobPattern {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        write!(b, "{}", self.0.glob())
+    }
+}
+
+impl FromStr for GlobPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        GlobPattern::new(s, false)
+    }
+}
+
+impl Serialize for GlobPattern {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for GlobPattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// 大文字と小文字を区別しない glob パターンとしてデシリアライズします。
+fn deserialize_case_insensitive<'de, D>(deserializer: D) -> Result<Option<GlobPattern>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    GlobPattern::new(&s, true)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ScoopBucket {
+    pub name: String,
+    pub source: String,
+}
+
+/// scoop が名前だけで追加できる既知のバケット (`scoop bucket known`) とそのソース。
+const KNOWN_BUCKETS: &[(&str, &str)] = &[
+    ("main", "https://github.com/ScoopInstaller/Main"),
+    ("extras", "https://github.com/ScoopInstaller/Extras"),
+    ("versions", "https://github.com/ScoopInstaller/Versions"),
+    ("nirsoft", "https://github.com/ScoopInstaller/Nirsoft"),
+    (
+        "sysinternals",
+        "https://github.com/niheaven/scoop-sysinternals",
+    ),
+    ("php", "https://github.com/ScoopInstaller/PHP"),
+    (
+        "nerd-fonts",
+        "https://github.com/matthewjberger/scoop-nerd-fonts",
+    ),
+    (
+        "nonportable",
+        "https://github.com/ScoopInstaller/Nonportable",
+    ),
+    ("java", "https://github.com/ScoopInstaller/Java"),
+    ("games", "https://github.com/Calinou/scoop-games"),
+];
+
+impl ScoopBucket {
+    /// 既知のバケットであれば、そのソースと合わせて返します。既知のバケットは設定ファイルで宣言し
+    /// なくても使える。
+    pub fn known(name: &str) -> Option<Self> {
+        KNOWN_BUCKETS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(name, source)| ScoopBucket {
+                name: name.to_string(),
+                source: source.to_string(),
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ScoopApp {
+    pub name: String,
+    pub bucket_name: String,
+}
+
+// ScoopApp は {bucket_name}/{name} の形式で表示する
+impl fmt::Display for ScoopApp {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        write!(b, "{}/{}", self.bucket_name, self.name)
+    }
+}
+
+impl Serialize for ScoopApp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for ScoopApp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.splitn(2, '/').collect();
+        let Ok([bucket_name, name]): Result<[_; 2], _> = parts.try_into() else {
+            return Err(format!("invalid format: expected `bucket/name`, got `{s}`"));
+        };
+
+        Ok(ScoopApp {
+            name: name.to_string(),
+            bucket_name: bucket_name.to_string(),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ScoopApp {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// 設定ファイルに記載されたアプリケーション。
+///
+/// `{bucket_name}/{name}@{version}` の短い形式のほか、属性を指定できるマッピング形式でも書ける。
+///
+/// ```yaml
+/// scoop_apps:
+///   - main/git
+///   - main/nodejs@20.11.0
+///   - name: python
+///     bucket: main
+///     version: 3.12.2
+///     arch: 32bit
+///     global: true
+///     hold: true
+///     optional: true
+/// ```
+///
+/// バージョンを固定した場合、インストール済みのバージョンが異なれば指定したバージョンで入れ直す。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AppSpec {
+    pub app: ScoopApp,
+    pub version: Option<String>,
+    /// インストールするアーキテクチャ。省略時は scoop の既定。
+    pub arch: Option<Arch>,
+    /// 全ユーザー向けにインストールするかどうか。
+    pub global: bool,
+    /// インストール後に現在のバージョンで固定 (scoop hold) するかどうか。
+    pub hold: bool,
+    /// 依存関係の解決に失敗しても無視してよいかどうか。
+    pub optional: bool,
+}
+
+/// scoop がサポートするアーキテクチャ。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Arch {
+    #[serde(rename = "64bit")]
+    X64,
+    #[serde(rename = "32bit")]
+    X86,
+    #[serde(rename = "arm64")]
+    Arm64,
+}
+
+impl Arch {
+    /// scoop がアーキテクチャを指定されなかったときに使うアーキテクチャを返します。scoop の
+    /// `default_architecture` 設定があればそれを、なければこのマシンのアーキテクチャを使う。
+    pub fn host() -> Self {
+        static HOST: OnceLock<Arch> = OnceLock::new();
+
+        *HOST.get_or_init(|| {
+            // 32 ビットのプロセスでは PROCESSOR_ARCHITECTURE が x86 になるので、OS の本来のアーキテ
+            // クチャが入る PROCESSOR_ARCHITEW6432 を優先する
+            let processor = env::var("PROCESSOR_ARCHITEW6432")
+                .or_else(|_| env::var("PROCESSOR_ARCHITECTURE"))
+                .ok();
+            let configured = scoop_config_value("default_architecture");
+            Self::default_for(configured.as_deref(), processor.as_deref())
+        })
+    }
+
+    /// scoop の設定値と、Windows が報告するプロセッサのアーキテクチャから既定のアーキテクチャを決
+    /// めます。
+    fn default_for(configured: Option<&str>, processor: Option<&str>) -> Self {
+        if let Some(arch) = configured.and_then(Self::parse_alias) {
+            return arch;
+        }
+
+        match processor.map(str::to_ascii_uppercase).as_deref() {
+            Some("AMD64") => Arch::X64,
+            Some("ARM64") => Arch::Arm64,
+            Some("X86") => Arch::X86,
+            // Windows 以外で動かしている場合など
+            _ if cfg!(target_arch = "aarch64") => Arch::Arm64,
+            _ if cfg!(target_arch = "x86") => Arch::X86,
+            _ => Arch::X64,
+        }
+    }
+
+    /// scoop の設定で使えるアーキテクチャの別名も含めてパースします。
+    fn parse_alias(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "64bit" | "64" | "x64" | "amd64" | "x86_64" | "x86-64" => Some(Arch::X64),
+            "32bit" | "32" | "x86" | "i386" | "386" | "i686" => Some(Arch::X86),
+            "arm64" | "arm" | "aarch64" => Some(Arch::Arm64),
+            _ => None,
+        }
+    }
+
+    /// scoop が出力するアーキテクチャ名をパースします。
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "64bit" => Some(Arch::X64),
+            "32bit" => Some(Arch::X86),
+            "arm64" => Some(Arch::Arm64),
+            _ => None,
+        }
+    }
+
+    /// scoop の `--arch` オプションに渡す名前を返します。
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Arch::X64 => "64bit",
+            Arch::X86 => "32bit",
+            Arch::Arm64 => "arm64",
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        b.write_str(self.as_str())
+    }
+}
+
+/// scoop の設定ファイル (`~/.config/scoop/config.json`) から文字列の設定値を読みます。
+fn scoop_config_value(key: &str) -> Option<String> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("USERPROFILE").map(|home| Path::new(&home).join(".config")))?;
+    let text = fs::read_to_string(config_home.join("scoop").join("config.json")).ok()?;
+    let config: serde_json::Value = serde_json::from_str(&text).ok()?;
+
+    config.get(key)?.as_str().map(str::to_string)
+}
+
+impl AppSpec {
+    /// バージョンを固定せず、属性も指定しないアプリケーションを作成します。
+    pub fn unpinned(app: ScoopApp) -> Self {
+        Self {
+            app,
+            version: None,
+            arch: None,
+            global: false,
+            hold: false,
+            optional: false,
+        }
+    }
+
+    /// 短い形式 ({bucket_name}/{name}@{version}) で表せるかどうか。
+    fn is_short_form(&self) -> bool {
+        self.arch.is_none() && !self.global && !self.hold && !self.optional
+    }
+
+    /// 計画の表示用に、属性を含めた説明を返します。
+    pub fn describe(&self) -> String {
+        let mut attributes = Vec::new();
+        if let Some(arch) = self.arch {
+            attributes.push(arch.to_string());
+        }
+        if self.global {
+            attributes.push("global".to_string());
+        }
+        if self.hold {
+            attributes.push("hold".to_string());
+        }
+
+        if attributes.is_empty() {
+            self.to_string()
+        } else {
+            format!("{self} [{}]", attributes.join(", "))
+        }
+    }
+}
+
+// AppSpec は {bucket_name}/{name}@{version} の形式で表示する。scoop install にもこの形式で渡す。
+impl fmt::Display for AppSpec {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(b, "{}@{}", self.app, version),
+            None => write!(b, "{}", self.app),
+        }
+    }
+}
+
+impl FromStr for AppSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (app, version) = match s.split_once('@') {
+            Some((app, version)) => (app, Some(version)),
+            None => (s, None),
+        };
+        if version.is_some_and(|version| version.is_empty()) {
+            return Err(format!("invalid format: empty version in `{s}`"));
+        }
+
+        Ok(AppSpec {
+            version: version.map(|version| version.to_string()),
+            ..AppSpec::unpinned(app.parse()?)
+        })
+    }
+}
+
+/// マッピング形式で書かれたアプリケーション。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetailedAppSpec {
+    /// アプリケーション名。`bucket` を省略した場合は `{bucket_name}/{name}` の形式で書く。
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bucket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arch: Option<Arch>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    global: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    hold: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    optional: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl TryFrom<DetailedAppSpec> for AppSpec {
+    type Error = String;
+
+    fn try_from(detailed: DetailedAppSpec) -> Result<Self, Self::Error> {
+        let app = match detailed.bucket {
+            Some(bucket_name) => ScoopApp {
+                name: detailed.name,
+                bucket_name,
+            },
+            None => detailed.name.parse()?,
+        };
+        if detailed.version.as_ref().is_some_and(|v| v.is_empty()) {
+            return Err(format!("invalid format: empty version for `{app}`"));
+        }
+
+        Ok(AppSpec {
+            app,
+            version: detailed.version,
+            arch: detailed.arch,
+            global: detailed.global,
+            hold: detailed.hold,
+            optional: detailed.optional,
+        })
+    }
+}
+
+impl From<&AppSpec> for DetailedAppSpec {
+    fn from(spec: &AppSpec) -> Self {
+        DetailedAppSpec {
+            name: spec.app.name.clone(),
+            bucket: Some(spec.app.bucket_name.clone()),
+            version: spec.version.clone(),
+            arch: spec.arch,
+            global: spec.global,
+            hold: spec.hold,
+            optional: spec.optional,
+        }
+    }
+}
+
+// 属性がなければ短い形式、あればマッピング形式で書き出す
+impl Serialize for AppSpec {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if self.is_short_form() {
+            serializer.collect_str(self)
+        } else {
+            DetailedAppSpec::from(self).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AppSpec {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // untagged enum だとどちらの形式でも失敗したときのエラーメッセージが分かりにくいので、
+        // Visitor で形式を振り分ける
+        struct AppSpecVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for AppSpecVisitor {
+            type Value = AppSpec;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("`bucket/name[@version]` or a mapping with `name` and `bucket`")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let detailed = DetailedAppSpec::deserialize(
+                    serde::de::value::MapAccessDeserializer::new(map),
+                )?;
+                detailed.try_into().map_err(serde::de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(AppSpecVisitor)
+    }
+}
+
+/// 設定ファイルを読み込みます。`include` に指定されたファイルも読み込み、一つの設定にまとめます。
+pub fn read_config_from_file<P: AsRef<Path>>(path: P, context: &ConfigContext) -> Result<Config> {
+    let loaded = load_config(path, context)?;
+    if !loaded.problems.is_empty() {
+        return Err(ConfigProblems {
+            problems: loaded.problems,
+        }
+        .into());
+    }
+
+    Ok(loaded.config)
+}
+
+/// 設定ファイルを読み込み、各項目がどのファイルのどこで宣言されたかと合わせて返します。ファイル
+/// を読めない場合などを除き、見つかった問題は [`LoadedConfig::problems`] に入れて返します。
+pub fn load_config<P: AsRef<Path>>(path: P, context: &ConfigContext) -> Result<LoadedConfig> {
+    let mut loader = ConfigLoader {
+        context: context.clone(),
+        ..ConfigLoader::default()
+    };
+    loader.load(path.as_ref())?;
+
+    Ok(LoadedConfig {
+        config: loader.config,
+        sources: loader.sources,
+        app_origins: loader.app_origins,
+        problems: loader.problems,
+    })
+}
+
+/// 読み込んだ設定と、各項目が宣言された位置。
+pub struct LoadedConfig {
+    pub config: Config,
+    sources: Vec<ConfigSource>,
+    app_origins: HashMap<ScoopApp, Origin>,
+    /// 矛盾した宣言など、読み込みを止めずに見つかった問題。
+    pub problems: Vec<ConfigDiagnostic>,
+}
+
+impl LoadedConfig {
+    /// アプリケーションが宣言された箇所を指すエラーを作成します。
+    pub fn app_diagnostic(
+        &self,
+        app: &ScoopApp,
+        message: impl Into<String>,
+        label: impl Into<String>,
+    ) -> Option<ConfigDiagnostic> {
+        let origin = self.app_origins.get(app)?;
+        Some(origin.diagnostic(&self.sources, message, label))
+    }
+}
+
+/// 項目が宣言された位置。
+#[derive(Debug, Clone)]
+struct Origin {
+    /// [`ConfigLoader::sources`] のインデックス。
+    source: usize,
+    path: Vec<Segment>,
+}
+
+impl Origin {
+    fn name<'a>(&self, sources: &'a [ConfigSource]) -> &'a str {
+        &sources[self.source].name
+    }
+
+    fn diagnostic(
+        &self,
+        sources: &[ConfigSource],
+        message: impl Into<String>,
+        label: impl Into<String>,
+    ) -> ConfigDiagnostic {
+        let source = &sources[self.source];
+        let span = source.span_of(&self.path).unwrap_or_else(|| (0, 0).into());
+        source.diagnostic(message, span, label)
+    }
+}
+
+/// 1 つの設定ファイルを `include` を展開せずに読み込みます。
+fn read_single_config_file(path: &Path) -> Result<(Config, ConfigSource)> {
+    let text = fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| {
+            miette!(
+                "failed to read app list from file {path}",
+                path = path.display()
+            )
+        })?;
+    let source = ConfigSource::new(path.display().to_string(), text);
+
+    match serde_yaml::from_str(source.text()) {
+        Ok(config) => Ok((config, source)),
+        Err(e) => {
+            let message = format!(
+                "failed to parse app list from file {path}",
+                path = path.display()
+            );
+
+            // serde_yaml は最初の問題で止まるので、YAML として読めるなら項目ごとに読み直して、すべ
+            // ての問題をまとめて報告する
+            let problems = serde_yaml::from_str(source.text())
+                .map(|value| collect_problems(&source, value, &[]))
+                .unwrap_or_default();
+            if !problems.is_empty() {
+                return Err(miette::Report::new(ConfigProblems { problems }).wrap_err(message));
+            }
+
+            let Some(location) = e.location() else {
+                return Err(e).into_diagnostic().wrap_err(message);
+            };
+
+            // serde_yaml のメッセージには位置が含まれるので、ラベルでは取り除く
+            let label = e.to_string();
+            let label = label
+                .rsplit_once(" at line ")
+                .map_or(&*label, |(label, _)| label)
+                .to_string();
+            let span = source.span_at(location.line(), location.column().saturating_sub(1));
+            Err(source.diagnostic(message, span, label).into())
+        }
+    }
+}
+
+/// 設定の各項目を個別にデシリアライズして、問題のある項目をすべて返します。`prefix` はファイル
+/// の中での設定の位置。
+fn collect_problems(
+    source: &ConfigSource,
+    value: serde_yaml::Value,
+    prefix: &[Segment],
+) -> Vec<ConfigDiagnostic> {
+    let problem = |path: &[Segment], e: serde_yaml::Error, label: String| {
+        let span = source.span_of(path).unwrap_or_else(|| (0, 0).into());
+        source.diagnostic(e.to_string(), span, label)
+    };
+    let serde_yaml::Value::Mapping(mapping) = value else {
+        return match serde_yaml::from_value::<Config>(value) {
+            Ok(_) => Vec::new(),
+            Err(e) => vec![problem(prefix, e, "invalid configuration".to_string())],
+        };
+    };
+
+    let mut problems = Vec::new();
+    for (key, value) in mapping {
+        let Some(name) = key.as_str().map(str::to_string) else {
+            continue;
+        };
+        let mut path = prefix.to_vec();
+        path.push(Segment::Key(name.clone()));
+        let item_path = |i: usize| {
+            let mut path = path.clone();
+            path.push(Segment::Index(i));
+            path
+        };
+
+        match (&*name, value) {
+            ("scoop_apps", serde_yaml::Value::Sequence(items)) => {
+                for (i, item) in items.into_iter().enumerate() {
+                    if let Err(e) = serde_yaml::from_value::<AppSpec>(item) {
+                        problems.push(problem(&item_path(i), e, "invalid app".to_string()));
+                    }
+                }
+            }
+            ("scoop_buckets", serde_yaml::Value::Sequence(items)) => {
+                for (i, item) in items.into_iter().enumerate() {
+                    if let Err(e) = serde_yaml::from_value::<ScoopBucket>(item) {
+                        problems.push(problem(&item_path(i), e, "invalid bucket".to_string()));
+                    }
+                }
+            }
+            ("sections", serde_yaml::Value::Sequence(items)) => {
+                for (i, item) in items.into_iter().enumerate() {
+                    problems.extend(collect_problems(source, item, &item_path(i)));
+                }
+            }
+            (_, value) => {
+                let mut single = serde_yaml::Mapping::new();
+                single.insert(key, value);
+                if let Err(e) = serde_yaml::from_value::<Config>(single.into()) {
+                    problems.push(problem(&path, e, format!("invalid `{name}`")));
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+/// `include` をたどって複数の設定ファイルを一つにまとめる。
+///
+/// 取り込まれたファイルを先に、取り込んだファイル自身を後にまとめる。バケットは名前、アプリケーシ
+/// ョンは `bucket/name` で同じものとみなし、内容が同じであれば一つにまとめ、異なればエラーにする。
+/// 同じファイルの中での重複は常にエラー。同じファイルが複数の経路で取り込まれた場合は一度だけ読み
+/// 込む。
+#[derive(Default)]
+struct ConfigLoader {
+    config: Config,
+    /// セクションの条件を評価するための環境。
+    context: ConfigContext,
+    /// 読み込みが完了したか、読み込み中のファイル。
+    loaded: HashSet<PathBuf>,
+    /// 読み込み中のファイル。循環した `include` を検出するため。
+    loading: Vec<PathBuf>,
+    /// 読み込んだファイルの内容。エラーの報告に使う。
+    sources: Vec<ConfigSource>,
+    /// 各項目を宣言した位置。エラーの報告に使う。
+    bucket_origins: HashMap<String, Origin>,
+    app_origins: HashMap<ScoopApp, Origin>,
+    default_arch_origin: Option<Origin>,
+    resolution_policy_origin: Option<Origin>,
+    update_origins: HashMap<ScoopApp, Origin>,
+    /// 矛盾した宣言など、読み込みを続けられる問題。最初の宣言を残して読み込みを続け、まとめて報告
+    /// する。
+    problems: Vec<ConfigDiagnostic>,
+}
+
+impl ConfigLoader {
+    fn load(&mut self, path: &Path) -> Result<()> {
+        let canonical = path.canonicalize().into_diagnostic().wrap_err_with(|| {
+            miette!(
+                "failed to read app list from file {path}",
+                path = path.display()
+            )
+        })?;
+        if let Some(pos) = self.loading.iter().position(|p| *p == canonical) {
+            let cycle = self.loading[pos..]
+                .iter()
+                .chain([&canonical])
+                .map(|p| p.display().to_string())
+                .join(" -> ");
+            bail!("circular include detected: {cycle}");
+        }
+        if !self.loaded.insert(canonical.clone()) {
+            return Ok(());
+        }
+
+        let (config, source) = read_single_config_file(path)?;
+        self.sources.push(source);
+        let source = self.sources.len() - 1;
+
+        self.loading.push(canonical);
+        self.apply(config, path, source, Vec::new())?;
+        self.loading.pop();
+
+        Ok(())
+    }
+
+    /// ファイルから読み込んだ設定を、取り込むファイル、自身、条件に一致するセクションの順にまとめ
+    /// ます。`prefix` はファイルの中での設定の位置。
+    fn apply(
+        &mut self,
+        mut config: Config,
+        path: &Path,
+        source: usize,
+        prefix: Vec<Segment>,
+    ) -> Result<()> {
+        if config
+            .when
+            .as_ref()
+            .is_some_and(|when| !when.matches(&self.context))
+        {
+            return Ok(());
+        }
+
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        for pattern in &config.include {
+            for included in resolve_include(base_dir, pattern)? {
+                self.load(&included).wrap_err_with(|| {
+                    miette!(
+                        "failed to include {included} from {path}",
+                        included = included.display(),
+                        path = path.display()
+                    )
+                })?;
+            }
+        }
+
+        let sections = mem::take(&mut config.sections);
+        self.merge(config, source, &prefix);
+
+        for (i, section) in sections.into_iter().enumerate() {
+            let mut prefix = prefix.clone();
+            prefix.extend([Segment::Key("sections".to_string()), Segment::Index(i)]);
+            self.apply(section, path, source, prefix)?;
+        }
+
+        Ok(())
+    }
+
+    fn merge(&mut self, config: Config, source: usize, prefix: &[Segment]) {
+        let origin_of = |key: &str, index: usize| {
+            let mut path = prefix.to_vec();
+            path.extend([Segment::Key(key.to_string()), Segment::Index(index)]);
+            Origin { source, path }
+        };
+
+        for (i, bucket) in config.scoop_buckets.into_iter().enumerate() {
+            let origin = origin_of("scoop_buckets", i);
+            if let Some(existing_origin) = self.bucket_origins.get(&bucket.name) {
+                // 同じファイルの中では、本体とセクションの間でも重複を許さない
+                if existing_origin.source == source {
+                    let problem = self.conflict(
+                        format!("bucket {} is declared more than once", bucket.name),
+                        &origin,
+                        "declared again here",
+                        existing_origin,
+                        "first declared here",
+                    );
+                    self.problems.push(problem);
+                    continue;
+                }
+
+                let existing = self
+                    .config
+                    .scoop_buckets
+                    .iter()
+                    .find(|b| b.name == bucket.name)
+                    .expect("bucket with origin must be merged");
+                if *existing != bucket {
+                    let problem = self.conflict(
+                        format!(
+                            "bucket {} is declared with different sources in {} and {}",
+                            bucket.name,
+                            existing_origin.name(&self.sources),
+                            origin.name(&self.sources)
+                        ),
+                        &origin,
+                        format!("declared with {} here", bucket.source),
+                        existing_origin,
+                        format!("declared with {} here", existing.source),
+                    );
+                    self.problems.push(problem);
+                    continue;
+                }
+                continue;
+            }
+
+            self.bucket_origins.insert(bucket.name.clone(), origin);
+            self.config.scoop_buckets.push(bucket);
+        }
+
+        for (i, spec) in config.scoop_apps.into_iter().enumerate() {
+            let origin = origin_of("scoop_apps", i);
+            if let Some(existing_origin) = self.app_origins.get(&spec.app) {
+                if existing_origin.source == source {
+                    let problem = self.conflict(
+                        format!("app {} is declared more than once", spec.app),
+                        &origin,
+                        "declared again here",
+                        existing_origin,
+                        "first declared here",
+                    );
+                    self.problems.push(problem);
+                    continue;
+                }
+
+                let existing = self
+                    .config
+                    .scoop_apps
+                    .iter()
+                    .find(|s| s.app == spec.app)
+                    .expect("app with origin must be merged");
+                if *existing != spec {
+                    let problem = self.conflict(
+                        format!(
+                            "app {} is declared differently in {} and {}",
+                            spec.app,
+                            existing_origin.name(&self.sources),
+                            origin.name(&self.sources)
+                        ),
+                        &origin,
+                        format!("declared as {} here", spec.describe()),
+                        existing_origin,
+                        format!("declared as {} here", existing.describe()),
+                    );
+                    self.problems.push(problem);
+                    continue;
+                }
+                continue;
+            }
+
+            self.app_origins.insert(spec.app.clone(), origin);
+            self.config.scoop_apps.push(spec);
+        }
+
+        if let Some(arch) = config.default_arch {
+            let mut path = prefix.to_vec();
+            path.push(Segment::Key("default_arch".to_string()));
+            let origin = Origin { source, path };
+            match (&self.default_arch_origin, self.config.default_arch) {
+                (Some(existing_origin), Some(existing)) if existing != arch => {
+                    let problem = self.conflict(
+                        format!(
+                            "default_arch is set differently in {} and {}",
+                            existing_origin.name(&self.sources),
+                            origin.name(&self.sources)
+                        ),
+                        &origin,
+                        format!("set to {arch} here"),
+                        existing_origin,
+                        format!("set to {existing} here"),
+                    );
+                    self.problems.push(problem);
+                }
+                _ => {
+                    self.config.default_arch = Some(arch);
+                    self.default_arch_origin = Some(origin);
+                }
+            }
+        }
+
+        if let Some(policy) = config.on_resolution_failure {
+            let mut path = prefix.to_vec();
+            path.push(Segment::Key("on_resolution_failure".to_string()));
+            let origin = Origin { source, path };
+            match (
+                &self.resolution_policy_origin,
+                self.config.on_resolution_failure,
+            ) {
+                (Some(existing_origin), Some(existing)) if existing != policy => {
+                    let problem = self.conflict(
+                        format!(
+                            "on_resolution_failure is set differently in {} and {}",
+                            existing_origin.name(&self.sources),
+                            origin.name(&self.sources)
+                        ),
+                        &origin,
+                        format!("set to {policy} here"),
+                        existing_origin,
+                        format!("set to {existing} here"),
+                    );
+                    self.problems.push(problem);
+                }
+                _ => {
+                    self.config.on_resolution_failure = Some(policy);
+                    self.resolution_policy_origin = Some(origin);
+                }
+            }
+        }
+
+        // 更新や削除の抑制は、どれか一つのファイルで有効にすれば有効になる
+        self.config.update.enabled |= config.update.enabled;
+        for (app, enabled) in config.update.apps {
+            let mut path = prefix.to_vec();
+            path.extend([
+                Segment::Key("update".to_string()),
+                Segment::Key("apps".to_string()),
+                Segment::Key(app.to_string()),
+            ]);
+            let origin = Origin { source, path };
+            match (
+                self.update_origins.get(&app),
+                self.config.update.apps.get(&app),
+            ) {
+                (Some(existing_origin), Some(&existing)) if existing != enabled => {
+                    let problem = self.conflict(
+                        format!(
+                            "update setting for {app} is set differently in {} and {}",
+                            existing_origin.name(&self.sources),
+                            origin.name(&self.sources)
+                        ),
+                        &origin,
+                        format!("set to {enabled} here"),
+                        existing_origin,
+                        format!("set to {existing} here"),
+                    );
+                    self.problems.push(problem);
+                }
+                _ => {
+                    self.config.update.apps.insert(app.clone(), enabled);
+                    self.update_origins.insert(app, origin);
+                }
+            }
+        }
+        self.config
+            .ignore
+            .scoop_apps
+            .extend(config.ignore.scoop_apps);
+        self.config
+            .ignore
+            .scoop_buckets
+            .extend(config.ignore.scoop_buckets);
+        self.config.additive |= config.additive;
+    }
+
+    /// 二か所の宣言が矛盾していることを示す問題を作成します。
+    fn conflict(
+        &self,
+        message: String,
+        origin: &Origin,
+        label: impl Into<String>,
+        other: &Origin,
+        other_label: impl Into<String>,
+    ) -> ConfigDiagnostic {
+        let related = other.diagnostic(&self.sources, "conflicting declaration", other_label);
+        origin
+            .diagnostic(&self.sources, message, label)
+            .with_related(related)
+    }
+}
+
+/// `include` の項目を、取り込むファイルのパスに展開します。相対パスは取り込む側のファイルがある
+/// ディレクトリからの相対パスとみなします。glob パターンに一致するファイルはパスの順に取り込み、
+/// 一致するファイルがなくてもエラーにしません。
+fn resolve_include(base_dir: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = base_dir.join(pattern);
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![path]);
+    }
+
+    let paths = glob::glob(&path.to_string_lossy())
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("invalid include pattern `{pattern}`"))?;
+    let mut paths = paths
+        .collect::<Result<Vec<_>, _>>()
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to expand include pattern `{pattern}`"))?;
+    paths.sort();
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use miette::Diagnostic;
+
+    use super::*;
+    use crate::test_util::{TempDir, app, write};
+
+    #[test]
+    fn parses_short_and_mapping_forms() {
+        let specs: Vec<AppSpec> = serde_yaml::from_str(
+            "
+- main/git@2.44.0
+- { name: extras/vscode, arch: 32bit, global: true }
+- { name: 7zip, bucket: main, hold: true, optional: true }
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            specs,
+            [
+                AppSpec {
+                    version: Some("2.44.0".to_string()),
+                    ..AppSpec::unpinned(app("main/git"))
+                },
+                AppSpec {
+                    arch: Some(Arch::X86),
+                    global: true,
+                    ..AppSpec::unpinned(app("extras/vscode"))
+                },
+                AppSpec {
+                    hold: true,
+                    optional: true,
+                    ..AppSpec::unpinned(app("main/7zip"))
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn serializes_apps_as_written() {
+        // 属性のないものは短い形式、あるものはバケットを分けたマッピング形式で書き出す
+        let yaml = "\
+- main/git@2.44.0
+- name: vscode
+  bucket: extras
+  arch: 32bit
+  global: true
+- name: 7zip
+  bucket: main
+  version: '23.01'
+  hold: true
+  optional: true
+";
+        let specs: Vec<AppSpec> = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(serde_yaml::to_string(&specs).unwrap(), yaml);
+    }
+
+    #[test]
+    fn collects_all_problems() {
+        let text = "\
+scoop_buckets:
+  - { name: main }
+scoop_apps:
+  - main/git
+  - nobucket
+unknown: true
+sections:
+  - when: { profile: work }
+    scoop_apps: [main/7zip@]
+";
+        let source = ConfigSource::new("test.yaml".to_string(), text.to_string());
+        let value = serde_yaml::from_str(text).unwrap();
+        let problems = collect_problems(&source, value, &[]);
+
+        let labels = problems
+            .iter()
+            .map(|problem| {
+                let label = problem.labels().unwrap().next().unwrap();
+                let line = text[..label.offset()].matches('\n').count() + 1;
+                (line, label.label().unwrap().to_string())
+            })
+            .collect_vec();
+        assert_eq!(
+            labels,
+            [
+                (2, "invalid bucket".to_string()),
+                (5, "invalid app".to_string()),
+                (6, "invalid `unknown`".to_string()),
+                (9, "invalid app".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn hostname_condition_ignores_case() {
+        let condition: Condition =
+            serde_yaml::from_str("{ hostname: build-*, username: Alice }").unwrap();
+        let context = ConfigContext {
+            hostname: Some("BUILD-01".to_string()),
+            username: Some("alice".to_string()),
+            profiles: Vec::new(),
+        };
+        assert!(condition.matches(&context));
+
+        // 無視するパターンなど、他の glob は大文字と小文字を区別する
+        let pattern: GlobPattern = "build-*".parse().unwrap();
+        assert!(!pattern.is_match("BUILD-01"));
+    }
+
+    #[test]
+    fn default_arch_prefers_scoop_config() {
+        assert_eq!(Arch::default_for(Some("32bit"), Some("AMD64")), Arch::X86);
+        assert_eq!(
+            Arch::default_for(Some("aarch64"), Some("AMD64")),
+            Arch::Arm64
+        );
+    }
+
+    #[test]
+    fn default_arch_follows_processor() {
+        assert_eq!(Arch::default_for(None, Some("AMD64")), Arch::X64);
+        assert_eq!(Arch::default_for(None, Some("ARM64")), Arch::Arm64);
+        assert_eq!(Arch::default_for(None, Some("x86")), Arch::X86);
+        // 設定値が不正なら無視する
+        assert_eq!(Arch::default_for(Some("sparc"), Some("ARM64")), Arch::Arm64);
+    }
+
+    fn context(profiles: &[&str]) -> ConfigContext {
+        ConfigContext {
+            hostname: None,
+            username: None,
+            profiles: profiles.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// 一時ディレクトリに設定ファイルを書き、`main.yaml` から読み込みます。
+    fn load(files: &[(&str, &str)], profiles: &[&str]) -> Result<LoadedConfig> {
+        let dir = TempDir::new();
+        for (name, content) in files {
+            write(&dir.path().join(name), content);
+        }
+        load_config(dir.path().join("main.yaml"), &context(profiles))
+    }
+
+    #[test]
+    fn includes_files_matching_globs() {
+        let loaded = load(
+            &[
+                (
+                    "main.yaml",
+                    "
+include: [roles/*.yaml, missing-*.yaml]
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps: [main/git]
+",
+                ),
+                (
+                    "roles/b.yaml",
+                    "
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps: [main/7zip]
+",
+                ),
+                (
+                    "roles/a.yaml",
+                    "
+scoop_buckets: [{ name: extras, source: https://example.com/extras }]
+scoop_apps: [extras/vscode, main/7zip]
+",
+                ),
+            ],
+            &[],
+        )
+        .unwrap();
+
+        // 取り込んだファイルをパスの順に、取り込んだファイル自身を後にまとめ、同じ宣言は一つにする
+        let config = loaded.config;
+        assert_eq!(
+            config
+                .scoop_buckets
+                .iter()
+                .map(|bucket| &*bucket.name)
+                .collect_vec(),
+            ["extras", "main"]
+        );
+        assert_eq!(
+            config.scoop_apps.iter().map(|spec| &spec.app).collect_vec(),
+            [&app("extras/vscode"), &app("main/7zip"), &app("main/git")]
+        );
+    }
+
+    /// 読み込み中に見つかった問題のメッセージを返します。
+    fn problems(files: &[(&str, &str)], profiles: &[&str]) -> Vec<String> {
+        let loaded = load(files, profiles).unwrap();
+        loaded.problems.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn reports_conflicts_between_files() {
+        let problems = self::problems(
+            &[
+                (
+                    "main.yaml",
+                    "{ include: [other.yaml], scoop_apps: [main/git, main/7zip] }",
+                ),
+                (
+                    "other.yaml",
+                    "scoop_apps: [{ name: main/git, global: true }, main/7zip@23.01]",
+                ),
+            ],
+            &[],
+        );
+        // 最初の矛盾で止めずに、すべて報告する
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("app main/git is declared differently in "));
+        assert!(problems[1].starts_with("app main/7zip is declared differently in "));
+
+        let problems = self::problems(
+            &[
+                (
+                    "main.yaml",
+                    "{ include: [other.yaml], scoop_buckets: [{ name: main, source: a }] }",
+                ),
+                ("other.yaml", "scoop_buckets: [{ name: main, source: b }]"),
+            ],
+            &[],
+        );
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("bucket main is declared with different sources in "));
+    }
+
+    #[test]
+    fn reports_duplicates_within_a_file() {
+        let problems = self::problems(&[("main.yaml", "scoop_apps: [main/git, main/git]")], &[]);
+        assert_eq!(problems, ["app main/git is declared more than once"]);
+
+        // 同じ内容でも、本体と適用されたセクションの間で重複していれば問題にする
+        let yaml = "
+scoop_apps: [main/git]
+sections:
+  - when: { profile: work }
+    scoop_apps: [main/git]
+";
+        let problems = self::problems(&[("main.yaml", yaml)], &["work"]);
+        assert_eq!(problems, ["app main/git is declared more than once"]);
+        assert!(self::problems(&[("main.yaml", yaml)], &[]).is_empty());
+
+        // 問題があれば、設定として読み込むときはエラーになる
+        let dir = TempDir::new();
+        write(&dir.path().join("main.yaml"), yaml);
+        assert!(read_config_from_file(dir.path().join("main.yaml"), &context(&["work"])).is_err());
+    }
+
+    #[test]
+    fn reports_conflicting_update_settings_with_both_locations() {
+        let loaded = load(
+            &[
+                (
+                    "main.yaml",
+                    "{ include: [other.yaml], update: { apps: { main/git: true } } }",
+                ),
+                ("other.yaml", "update: { apps: { main/git: false } }"),
+            ],
+            &[],
+        )
+        .unwrap();
+        let [problem] = &loaded.problems[..] else {
+            panic!("expected a single problem: {:?}", loaded.problems);
+        };
+        assert!(
+            problem
+                .to_string()
+                .starts_with("update setting for main/git is set differently in ")
+        );
+
+        // 後から読み込んだ main.yaml の設定を指し、先に読み込んだ other.yaml の設定を関連付ける
+        let label = |diagnostic: &dyn Diagnostic| {
+            let label = diagnostic.labels().unwrap().next().unwrap();
+            label.label().unwrap().to_string()
+        };
+        assert_eq!(label(problem), "set to true here");
+        let related = problem.related().unwrap().collect_vec();
+        assert_eq!(related.len(), 1);
+        assert_eq!(label(related[0]), "set to false here");
+    }
+
+    #[test]
+    fn detects_circular_include() {
+        let err = load(
+            &[
+                ("main.yaml", "include: [a.yaml]"),
+                ("a.yaml", "include: [b.yaml]"),
+                ("b.yaml", "include: [a.yaml]"),
+            ],
+            &[],
+        )
+        .err()
+        .unwrap();
+        let cause = err.chain().last().unwrap().to_string();
+        let cycle = cause
+            .strip_prefix("circular include detected: ")
+            .unwrap()
+            .split(" -> ")
+            .map(|path| Path::new(path).file_name().unwrap().to_str().unwrap())
+            .collect_vec();
+        assert_eq!(cycle, ["a.yaml", "b.yaml", "a.yaml"]);
+    }
+}
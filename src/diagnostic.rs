@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use miette::{Diagnostic, NamedSource, SourceSpan};
+use thiserror::Error;
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
+
+/// YAML ドキュメント中のノードの位置を表すパスの要素。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// 設定ファイルの内容と、その中の各ノードの位置。
+///
+/// serde_yaml はデシリアライズした値がファイルのどこにあったかを教えてくれないので、同じ内容を
+/// yaml-rust2 のイベントパーサーでもう一度読んで位置を記録しておく。エラーを報告するときに、問題
+/// のある項目を指し示すため。
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub name: String,
+    text: String,
+    /// ノードのパスと、その開始位置のバイトオフセット。
+    positions: HashMap<Vec<Segment>, usize>,
+}
+
+impl ConfigSource {
+    pub fn new(name: String, text: String) -> Self {
+        let mut recorder = PositionRecorder::default();
+        // 構文エラーは serde_yaml 側で位置付きで報告するので、ここでは読めたところまでを使う
+        let _ = Parser::new_from_str(&text).load(&mut recorder, false);
+        let positions = recorder
+            .positions
+            .into_iter()
+            .map(|(path, marker)| (path, offset_at(&text, marker.line(), marker.col())))
+            .collect();
+
+        Self {
+            name,
+            text,
+            positions,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// ノードの開始位置からその行の終わりまでを返します。
+    pub fn span_of(&self, path: &[Segment]) -> Option<SourceSpan> {
+        self.positions
+            .get(path)
+            .map(|&offset| self.span_to_line_end(offset))
+    }
+
+    /// 行 (1 始まり) と桁 (0 始まり、文字単位) の位置からその行の終わりまでを返します。
+    pub fn span_at(&self, line: usize, column: usize) -> SourceSpan {
+        self.span_to_line_end(offset_at(&self.text, line, column))
+    }
+
+    fn span_to_line_end(&self, offset: usize) -> SourceSpan {
+        let rest = &self.text[offset..];
+        let line = rest.lines().next().unwrap_or("");
+        (offset, line.trim_end().len()).into()
+    }
+
+    /// このファイルの指定の位置を指すエラーを作成します。
+    pub fn diagnostic(
+        &self,
+        message: impl Into<String>,
+        span: SourceSpan,
+        label: impl Into<String>,
+    ) -> ConfigDiagnostic {
+        ConfigDiagnostic {
+            message: message.into(),
+            source_code: NamedSource::new(&self.name, self.text.clone()).with_language("yaml"),
+            span,
+            label: label.into(),
+            related: Vec::new(),
+        }
+    }
+}
+
+/// 行 (1 始まり) と桁 (0 始まり、文字単位) の位置をバイトオフセットに変換します。
+fn offset_at(text: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    let line_text = text[line_start..].lines().next().unwrap_or("");
+
+    line_start
+        + line_text
+            .char_indices()
+            .nth(column)
+            .map_or(line_text.len(), |(i, _)| i)
+}
+
+/// 設定ファイル中の項目を指し示すエラー。
+#[derive(Debug, Error, Diagnostic)]
+#[error("{message}")]
+pub struct ConfigDiagnostic {
+    message: String,
+    #[source_code]
+    source_code: NamedSource<String>,
+    #[label("{label}")]
+    span: SourceSpan,
+    label: String,
+    #[related]
+    related: Vec<ConfigDiagnostic>,
+}
+
+impl ConfigDiagnostic {
+    /// 関連する箇所 (最初に宣言された場所など) を追加します。
+    pub fn with_related(mut self, related: ConfigDiagnostic) -> Self {
+        self.related.push(related);
+        self
+    }
+}
+
+/// 設定ファイルに見つかった複数の問題。
+#[derive(Debug, Error, Diagnostic)]
+#[error("found {} problem(s) in the configuration", problems.len())]
+pub struct ConfigProblems {
+    #[related]
+    pub problems: Vec<ConfigDiagnostic>,
+}
+
+/// イベントを受け取りながら、各ノードのパスと開始位置を記録する。
+#[derive(Default)]
+struct PositionRecorder {
+    stack: Vec<Frame>,
+    positions: HashMap<Vec<Segment>, Marker>,
+}
+
+enum Frame {
+    Sequence {
+        path: Vec<Segment>,
+        next_index: usize,
+    },
+    Mapping {
+        path: Vec<Segment>,
+        /// 値を待っているキー。`None` のときは次のノードがキーになる。
+        key: Option<String>,
+    },
+}
+
+impl PositionRecorder {
+    /// 次に現れるノードのパスを返します。マッピングのキーであれば `None` を返します。
+    fn next_path(&mut self, scalar: Option<&str>) -> Option<Vec<Segment>> {
+        match self.stack.last_mut() {
+            None => Some(Vec::new()),
+            Some(Frame::Sequence { path, next_index }) => {
+                let mut path = path.clone();
+                path.push(Segment::Index(*next_index));
+                *next_index += 1;
+                Some(path)
+            }
+            Some(Frame::Mapping { path, key }) => match key.take() {
+                Some(key) => {
+                    let mut path = path.clone();
+                    path.push(Segment::Key(key));
+                    Some(path)
+                }
+                None => {
+                    // スカラー以外のキーは設定ファイルでは使わないので、空文字列として扱う
+                    *key = Some(scalar.unwrap_or_default().to_string());
+                    None
+                }
+            },
+        }
+    }
+}
+
+impl PositionRecorder {
+    /// ブロック形式のマッピングの開始位置は最初の値の位置になるので、キーの位置に直します。
+    fn record_key(&mut self, mark: Marker) {
+        if let Some(Frame::Mapping { path, .. }) = self.stack.last() {
+            let position = self.positions.entry(path.clone()).or_insert(mark);
+            if mark.index() < position.index() {
+                *position = mark;
+            }
+        }
+    }
+}
+
+impl MarkedEventReceiver for PositionRecorder {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        match ev {
+            Event::Scalar(value, ..) => match self.next_path(Some(&value)) {
+                Some(path) => {
+                    self.positions.insert(path, mark);
+                }
+                None => self.record_key(mark),
+            },
+            Event::Alias(_) => {
+                if let Some(path) = self.next_path(None) {
+                    self.positions.insert(path, mark);
+                }
+            }
+            Event::SequenceStart(..) | Event::MappingStart(..) => {
+                let is_sequence = matches!(ev, Event::SequenceStart(..));
+                let path = self.next_path(None).unwrap_or_default();
+                self.positions.insert(path.clone(), mark);
+                self.stack.push(if is_sequence {
+                    Frame::Sequence {
+                        path,
+                        next_index: 0,
+                    }
+                } else {
+                    Frame::Mapping { path, key: None }
+                });
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
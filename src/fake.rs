@@ -0,0 +1,566 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use colored::*;
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result, WrapErr, bail, miette};
+use serde::Deserialize;
+
+use crate::backend::{Backend, OutdatedApp};
+use crate::client::ExecResult;
+use crate::config::{AppSpec, Arch, ScoopApp, ScoopBucket};
+use crate::{InstalledApp, InstalledThings, make_label};
+
+/// fake バックエンドの状態を読み込むファイルを指定する環境変数。
+pub const FAKE_STATE_ENV: &str = "DECLARATIVE_SCOOP_FAKE_STATE";
+
+/// scoop を実際に呼び出さず、メモリ上でバケットとアプリケーションの状態をシミュレートするバックエ
+/// ンド。
+///
+/// 実行した操作は順番に記録され、コマンドの終了時に一覧として表示される。また、特定の操作を失敗
+/// させるように設定できるため、実際の環境では再現しづらいエラー経路も確認できる。
+pub struct FakeBackend {
+    state: FakeState,
+    /// 実行した操作の記録。`open_session` で開いたセッションとも共有する。
+    operations: Arc<Mutex<Vec<Operation>>>,
+}
+
+/// fake バックエンドの初期状態。YAML ファイルから読み込む。
+///
+/// ```yaml
+/// buckets:
+///   main:
+///     source: https://github.com/ScoopInstaller/Main
+///     apps:
+///       git: { version: 2.44.0 }
+///       nodejs: { version: 20.11.0, depends: [main/7zip] }
+///       7zip: { version: "23.01" }
+/// installed_buckets: [main]
+/// installed_apps: [main/git@2.43.0]
+/// failures:
+///   - operation: install
+///     target: main/nodejs
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FakeState {
+    /// 利用可能なバケットとそのマニフェスト。
+    pub buckets: HashMap<String, FakeBucket>,
+    /// 追加済みのバケット名。
+    pub installed_buckets: Vec<String>,
+    /// インストール済みのアプリケーション。バージョンを省略した場合はマニフェストのバージョンがイ
+    /// ンストールされているものとする。
+    pub installed_apps: Vec<AppSpec>,
+    /// 失敗させる操作。
+    pub failures: Vec<Failure>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FakeBucket {
+    pub source: String,
+    pub apps: HashMap<String, FakeManifest>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FakeManifest {
+    #[serde(default = "default_manifest_version")]
+    pub version: String,
+    /// 直接の依存関係。
+    #[serde(default)]
+    pub depends: Vec<ScoopApp>,
+}
+
+fn default_manifest_version() -> String {
+    "1.0.0".to_string()
+}
+
+/// 失敗させる操作の指定。`target` を省略した場合はその種類の操作すべてが失敗する。
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Failure {
+    pub operation: OperationKind,
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    Exec,
+    ListInstalled,
+    Depends,
+    Status,
+    Install,
+    Hold,
+    Unhold,
+    Update,
+    Uninstall,
+    AddBucket,
+    RemoveBucket,
+}
+
+/// fake バックエンドに対して実行された操作。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Exec(Vec<String>),
+    ListInstalled,
+    Depends(ScoopApp),
+    Status,
+    Install(Vec<AppSpec>),
+    Hold(Vec<AppSpec>),
+    Unhold(Vec<AppSpec>),
+    Update(Vec<AppSpec>),
+    Uninstall(Vec<AppSpec>),
+    AddBucket(ScoopBucket),
+    RemoveBuckets(Vec<ScoopBucket>),
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Exec(args) => write!(b, "exec {}", args.join(" ")),
+            Operation::ListInstalled => write!(b, "export"),
+            Operation::Depends(app) => write!(b, "depends {app}"),
+            Operation::Status => write!(b, "status"),
+            Operation::Install(apps) => {
+                write!(
+                    b,
+                    "install {}",
+                    apps.iter().map(AppSpec::describe).join(" ")
+                )
+            }
+            Operation::Hold(apps) => {
+                write!(b, "hold {}", apps.iter().map(AppSpec::describe).join(" "))
+            }
+            Operation::Unhold(apps) => {
+                write!(b, "unhold {}", apps.iter().map(AppSpec::describe).join(" "))
+            }
+            Operation::Update(apps) => {
+                write!(b, "update {}", apps.iter().map(AppSpec::describe).join(" "))
+            }
+            Operation::Uninstall(apps) => {
+                write!(
+                    b,
+                    "uninstall {}",
+                    apps.iter().map(AppSpec::describe).join(" ")
+                )
+            }
+            Operation::AddBucket(bucket) => {
+                write!(b, "bucket add {} {}", bucket.name, bucket.source)
+            }
+            Operation::RemoveBuckets(buckets) => {
+                write!(b, "bucket rm {}", buckets.iter().map(|b| &b.name).join(" "))
+            }
+        }
+    }
+}
+
+impl FakeBackend {
+    pub fn new(state: FakeState) -> Self {
+        Self {
+            state,
+            operations: Arc::default(),
+        }
+    }
+
+    /// YAML ファイルから初期状態を読み込んで fake バックエンドを作成します。
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).into_diagnostic().wrap_err_with(|| {
+            miette!(
+                "failed to read fake state from file {path}",
+                path = path.display()
+            )
+        })?;
+        let state = serde_yaml::from_reader(BufReader::new(file))
+            .into_diagnostic()
+            .wrap_err_with(|| {
+                miette!(
+                    "failed to parse fake state from file {path}",
+                    path = path.display()
+                )
+            })?;
+
+        Ok(Self::new(state))
+    }
+
+    /// これまでに実行された操作を実行順に返します。
+    pub fn operations(&self) -> Vec<Operation> {
+        self.operations.lock().unwrap().clone()
+    }
+
+    fn record(&self, operation: Operation) {
+        self.operations.lock().unwrap().push(operation);
+    }
+
+    fn check_failure(&self, kind: OperationKind, target: &str) -> Result<()> {
+        let injected = self.state.failures.iter().any(|failure| {
+            failure.operation == kind && failure.target.as_deref().is_none_or(|t| t == target)
+        });
+        if injected {
+            bail!("injected failure: {kind:?} {target}");
+        }
+
+        Ok(())
+    }
+
+    fn bucket_source(&self, name: &str) -> String {
+        self.state
+            .buckets
+            .get(name)
+            .map(|bucket| bucket.source.clone())
+            .unwrap_or_default()
+    }
+
+    /// 追加済みのバケットからマニフェストを探します。
+    fn manifest(&self, app: &ScoopApp) -> Result<&FakeManifest> {
+        if !self.state.installed_buckets.contains(&app.bucket_name) {
+            bail!("Couldn't find manifest for {app}: bucket is not added");
+        }
+
+        self.state
+            .buckets
+            .get(&app.bucket_name)
+            .and_then(|bucket| bucket.apps.get(&app.name))
+            .ok_or_else(|| miette!("Couldn't find manifest for {app}"))
+    }
+
+    /// アプリケーションがインストール済みかどうかを返します。scoop と同様に、ユーザー単位のインス
+    /// トールでは global にインストールされているものもインストール済みとみなす。
+    fn is_installed(&self, app: &ScoopApp, global: bool) -> bool {
+        self.state
+            .installed_apps
+            .iter()
+            .any(|spec| spec.app == *app && (spec.global || !global))
+    }
+
+    /// 指定のスコープにインストールされているアプリケーションの位置を返します。
+    fn position(&self, app: &ScoopApp, global: bool) -> Option<usize> {
+        self.state
+            .installed_apps
+            .iter()
+            .position(|spec| spec.app == *app && spec.global == global)
+    }
+
+    /// インストール済みのアプリケーションのバージョンを返します。
+    fn installed_version(&self, spec: &AppSpec) -> String {
+        spec.version.clone().unwrap_or_else(|| {
+            self.state
+                .buckets
+                .get(&spec.app.bucket_name)
+                .and_then(|bucket| bucket.apps.get(&spec.app.name))
+                .map(|manifest| manifest.version.clone())
+                .unwrap_or_else(default_manifest_version)
+        })
+    }
+}
+
+impl Backend for FakeBackend {
+    fn exec(&mut self, commands: &[&str]) -> Result<ExecResult> {
+        self.record(Operation::Exec(
+            commands.iter().map(|c| c.to_string()).collect(),
+        ));
+        self.check_failure(OperationKind::Exec, &commands.join(" "))?;
+
+        bail!(
+            "fake backend cannot run raw scoop commands: {}",
+            commands.join(" ")
+        )
+    }
+
+    /// 操作の記録は共有するが、状態はその時点の複製になる。セッションは依存関係の解決にしか使わ
+    /// ないので、状態の変更が元に反映されなくても問題ない。
+    fn open_session(&self) -> Result<Box<dyn Backend>> {
+        Ok(Box::new(Self {
+            state: self.state.clone(),
+            operations: Arc::clone(&self.operations),
+        }))
+    }
+
+    fn list_installed(&mut self) -> Result<InstalledThings> {
+        self.record(Operation::ListInstalled);
+        self.check_failure(OperationKind::ListInstalled, "")?;
+
+        let installed_apps = |global: bool| {
+            self.state
+                .installed_apps
+                .iter()
+                .filter(|spec| spec.global == global)
+                .map(|spec| {
+                    let installed = InstalledApp {
+                        version: self.installed_version(spec),
+                        arch: spec.arch.unwrap_or_else(Arch::host),
+                        held: spec.hold,
+                    };
+                    (spec.app.clone(), installed)
+                })
+                .collect()
+        };
+
+        Ok(InstalledThings {
+            scoop_buckets: self
+                .state
+                .installed_buckets
+                .iter()
+                .map(|name| ScoopBucket {
+                    name: name.clone(),
+                    source: self.bucket_source(name),
+                })
+                .collect(),
+            scoop_apps: installed_apps(false),
+            global_scoop_apps: installed_apps(true),
+        })
+    }
+
+    fn dependencies_of(&mut self, app: &ScoopApp) -> Result<HashSet<ScoopApp>> {
+        self.record(Operation::Depends(app.clone()));
+        self.check_failure(OperationKind::Depends, &app.to_string())?;
+
+        // scoop depends と同様に、アプリケーション自身と間接的な依存関係も含める
+        let mut found = HashSet::new();
+        let mut to_visit = vec![app.clone()];
+        while let Some(app) = to_visit.pop() {
+            if found.contains(&app) {
+                continue;
+            }
+
+            to_visit.extend(self.manifest(&app)?.depends.iter().cloned());
+            found.insert(app);
+        }
+
+        Ok(found)
+    }
+
+    /// 依存関係は状態ファイルで決まり、実際のバケットとは関係ないので、キャッシュすると実際の
+    /// scoop の結果と混ざってしまう。
+    fn caches_dependencies(&self) -> bool {
+        false
+    }
+
+    fn outdated_apps(&mut self) -> Result<Vec<OutdatedApp>> {
+        self.record(Operation::Status);
+        self.check_failure(OperationKind::Status, "")?;
+
+        Ok(self
+            .state
+            .installed_apps
+            .iter()
+            .filter_map(|spec| {
+                let manifest = self.manifest(&spec.app).ok()?;
+                let installed_version = self.installed_version(spec);
+                (installed_version != manifest.version).then(|| OutdatedApp {
+                    app: spec.app.clone(),
+                    installed_version,
+                    latest_version: manifest.version.clone(),
+                })
+            })
+            .collect())
+    }
+
+    fn install_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        self.record(Operation::Install(apps.to_vec()));
+
+        // scoop と同様に依存関係も合わせてインストールする
+        let mut to_install = VecDeque::from(apps.to_vec());
+        while let Some(spec) = to_install.pop_front() {
+            if self.is_installed(&spec.app, spec.global) {
+                continue;
+            }
+
+            // バージョンを指定したインストールだけを失敗させられるよう、`bucket/name@version` でも
+            // 照合する
+            self.check_failure(OperationKind::Install, &spec.app.to_string())
+                .and_then(|()| self.check_failure(OperationKind::Install, &spec.to_string()))
+                .wrap_err("failed to install applications")?;
+            let manifest = self
+                .manifest(&spec.app)
+                .wrap_err("failed to install applications")?;
+            let version = spec
+                .version
+                .clone()
+                .unwrap_or_else(|| manifest.version.clone());
+            // 依存関係は依存元と同じスコープにインストールされる
+            to_install.extend(manifest.depends.iter().map(|dep| AppSpec {
+                global: spec.global,
+                ..AppSpec::unpinned(dep.clone())
+            }));
+            self.state.installed_apps.push(AppSpec {
+                version: Some(version),
+                hold: false,
+                optional: false,
+                ..spec
+            });
+        }
+
+        Ok(())
+    }
+
+    fn hold_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        self.record(Operation::Hold(apps.to_vec()));
+
+        for spec in apps {
+            self.check_failure(OperationKind::Hold, &spec.app.to_string())
+                .wrap_err("failed to hold applications")?;
+            let Some(pos) = self.position(&spec.app, spec.global) else {
+                bail!(
+                    "failed to hold applications: '{}' isn't installed",
+                    spec.app
+                );
+            };
+            self.state.installed_apps[pos].hold = true;
+        }
+
+        Ok(())
+    }
+
+    fn unhold_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        self.record(Operation::Unhold(apps.to_vec()));
+
+        for spec in apps {
+            self.check_failure(OperationKind::Unhold, &spec.app.to_string())
+                .wrap_err("failed to unhold applications")?;
+            let Some(pos) = self.position(&spec.app, spec.global) else {
+                bail!(
+                    "failed to unhold applications: '{}' isn't installed",
+                    spec.app
+                );
+            };
+            self.state.installed_apps[pos].hold = false;
+        }
+
+        Ok(())
+    }
+
+    fn update_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        self.record(Operation::Update(apps.to_vec()));
+
+        for AppSpec { app, global, .. } in apps {
+            self.check_failure(OperationKind::Update, &app.to_string())
+                .wrap_err("failed to update applications")?;
+            let latest_version = self
+                .manifest(app)
+                .wrap_err("failed to update applications")?
+                .version
+                .clone();
+            let Some(pos) = self.position(app, *global) else {
+                bail!("failed to update applications: '{app}' isn't installed");
+            };
+            // scoop と同様に、固定されているアプリケーションは更新しない
+            let installed = &mut self.state.installed_apps[pos];
+            if !installed.hold {
+                installed.version = Some(latest_version);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn uninstall_apps(&mut self, apps: &[AppSpec]) -> Result<()> {
+        self.record(Operation::Uninstall(apps.to_vec()));
+
+        for AppSpec { app, global, .. } in apps {
+            self.check_failure(OperationKind::Uninstall, &app.to_string())
+                .wrap_err("failed to uninstall applications")?;
+            let Some(pos) = self.position(app, *global) else {
+                bail!("failed to uninstall applications: '{app}' isn't installed");
+            };
+            self.state.installed_apps.remove(pos);
+        }
+
+        Ok(())
+    }
+
+    fn add_bucket(&mut self, bucket: &ScoopBucket) -> Result<()> {
+        self.record(Operation::AddBucket(bucket.clone()));
+        self.check_failure(OperationKind::AddBucket, &bucket.name)
+            .wrap_err_with(|| miette!("failed to install bucket {}", bucket.name))?;
+
+        if self.state.installed_buckets.contains(&bucket.name) {
+            bail!(
+                "failed to install bucket {}: bucket already exists",
+                bucket.name
+            );
+        }
+        self.state.installed_buckets.push(bucket.name.clone());
+
+        Ok(())
+    }
+
+    fn remove_buckets(&mut self, buckets: &[ScoopBucket]) -> Result<()> {
+        self.record(Operation::RemoveBuckets(buckets.to_vec()));
+
+        for bucket in buckets {
+            self.check_failure(OperationKind::RemoveBucket, &bucket.name)
+                .wrap_err("failed to uninstall buckets")?;
+            let Some(pos) = self
+                .state
+                .installed_buckets
+                .iter()
+                .position(|name| *name == bucket.name)
+            else {
+                bail!(
+                    "failed to uninstall buckets: '{}' bucket not found",
+                    bucket.name
+                );
+            };
+            self.state.installed_buckets.remove(pos);
+        }
+
+        Ok(())
+    }
+
+    /// 実行された操作の一覧を表示します。
+    fn finish(&self) {
+        eprintln!();
+        eprintln!("{} operations on fake backend", make_label("Recorded"));
+        for (i, operation) in self.operations().iter().enumerate() {
+            eprintln!("{:>8} {}", format!("{}.", i + 1).cyan(), operation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::app;
+
+    #[test]
+    fn depends_includes_app_and_indirect_dependencies() {
+        let state: FakeState = serde_yaml::from_str(
+            "
+buckets:
+  main:
+    apps:
+      a: { depends: [main/b] }
+      b: { depends: [main/c] }
+      c: { depends: [main/a] }
+      d: {}
+installed_buckets: [main]
+",
+        )
+        .unwrap();
+        let mut fake = FakeBackend::new(state);
+        assert_eq!(
+            fake.dependencies_of(&app("main/a")).unwrap(),
+            HashSet::from([app("main/a"), app("main/b"), app("main/c")])
+        );
+        assert_eq!(
+            fake.dependencies_of(&app("main/d")).unwrap(),
+            HashSet::from([app("main/d")])
+        );
+        assert_eq!(
+            fake.operations(),
+            [
+                Operation::Depends(app("main/a")),
+                Operation::Depends(app("main/d"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,704 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Serialize;
+
+use crate::config::ScoopApp;
+use crate::{InstalledThings, RequiredThings, ThingsToUninstall};
+
+/// 解決した依存関係のグラフ。
+///
+/// `scoop depends` は間接的な依存関係も含めて返すので、そのままでは「何を経由して必要になったか」
+/// がわからない。他の依存関係を経由して到達できる辺を取り除き、直接の依存関係だけを辺として持つ。
+pub struct DependencyGraph {
+    /// 設定ファイルに記載されたアプリケーションと、global かどうか。名前順。
+    roots: Vec<(ScoopApp, bool)>,
+    /// アプリケーションの直接の依存関係。名前順。
+    edges: HashMap<ScoopApp, Vec<ScoopApp>>,
+}
+
+impl DependencyGraph {
+    pub fn new(required: &RequiredThings) -> Self {
+        let closure = |app: &ScoopApp, dep: &ScoopApp| {
+            required
+                .scoop_apps
+                .get(app)
+                .is_some_and(|deps| deps.contains(dep))
+        };
+
+        let edges = required
+            .scoop_apps
+            .iter()
+            .map(|(app, deps)| {
+                // 別の依存関係 via から到達できるものは間接的な依存関係とみなす。ただし dep と via
+                // が循環している場合はどちらも直接の依存関係として残す。via が app と循環している
+                // 場合も、via 側でも app を経由するとみなされて辺が消えてしまうので経由地にしない
+                let direct = deps
+                    .iter()
+                    .filter(|dep| *dep != app)
+                    .filter(|dep| {
+                        !deps.iter().any(|via| {
+                            via != app
+                                && via != *dep
+                                && !closure(via, app)
+                                && closure(via, dep)
+                                && !closure(dep, via)
+                        })
+                    })
+                    .cloned()
+                    .sorted_by_key(|dep| dep.to_string())
+                    .collect();
+                (app.clone(), direct)
+            })
+            .collect();
+
+        let roots = required
+            .app_specs
+            .values()
+            .map(|spec| (spec.app.clone(), spec.global))
+            .sorted_by_key(|(app, _)| app.to_string())
+            .collect();
+
+        Self { roots, edges }
+    }
+
+    /// アプリケーションの直接の依存関係を名前順に返します。
+    pub fn dependencies_of(&self, app: &ScoopApp) -> &[ScoopApp] {
+        self.edges.get(app).map_or(&[], Vec::as_slice)
+    }
+
+    /// 設定ファイルに記載されたアプリケーションから `target` までの経路を、最大 `limit` 個返しま
+    /// す。各経路は記載されたアプリケーションから始まり `target` で終わる。経路が `limit` 個より多
+    /// ければ、二つ目の値として `true` を返す。
+    pub fn paths_to(&self, target: &ScoopApp, limit: usize) -> (Vec<(bool, Vec<ScoopApp>)>, bool) {
+        // 経路の数は依存関係の深さに対して指数的に増えうるので、target に到達できないアプリケーシ
+        // ョンはたどらず、上限に達したら打ち切る
+        let reaching = self.apps_reaching(target);
+        let mut paths = Vec::new();
+        let mut truncated = false;
+        for (root, global) in &self.roots {
+            if !reaching.contains(root) {
+                continue;
+            }
+
+            let mut path = vec![root.clone()];
+            let completed = self.collect_paths(target, &reaching, &mut path, &mut |path| {
+                if paths.len() == limit {
+                    truncated = true;
+                    return false;
+                }
+                paths.push((*global, path.to_vec()));
+                true
+            });
+            if !completed {
+                break;
+            }
+        }
+
+        (paths, truncated)
+    }
+
+    /// `target` 自身と、依存関係をたどって `target` に到達できるアプリケーションを返します。
+    fn apps_reaching<'a>(&'a self, target: &'a ScoopApp) -> HashSet<&'a ScoopApp> {
+        let mut dependents: HashMap<&ScoopApp, Vec<&ScoopApp>> = HashMap::new();
+        for (app, deps) in &self.edges {
+            for dep in deps {
+                dependents.entry(dep).or_default().push(app);
+            }
+        }
+
+        let mut reaching = HashSet::from([target]);
+        let mut to_visit = VecDeque::from([target]);
+        while let Some(app) = to_visit.pop_front() {
+            for dependent in dependents.get(app).into_iter().flatten() {
+                if reaching.insert(dependent) {
+                    to_visit.push_back(dependent);
+                }
+            }
+        }
+
+        reaching
+    }
+
+    /// `path` の続きで `target` に到達する経路を探して `found` に渡します。`found` が `false` を
+    /// 返したら探索をやめて `false` を返します。
+    fn collect_paths(
+        &self,
+        target: &ScoopApp,
+        reaching: &HashSet<&ScoopApp>,
+        path: &mut Vec<ScoopApp>,
+        found: &mut impl FnMut(&[ScoopApp]) -> bool,
+    ) -> bool {
+        let current = path.last().cloned().expect("path is never empty");
+        if current == *target {
+            return found(path);
+        }
+
+        for dep in self.dependencies_of(&current) {
+            // 循環している場合に同じ経路を回り続けないようにする
+            if !reaching.contains(dep) || path.contains(dep) {
+                continue;
+            }
+
+            path.push(dep.clone());
+            let completed = self.collect_paths(target, reaching, path, found);
+            path.pop();
+            if !completed {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// グラフに描くアプリケーションの状態。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodeStatus {
+    /// 必要で、インストール済み。
+    Installed,
+    /// 必要だが、まだインストールされていない。
+    ToInstall,
+    /// 設定ファイルに記載されておらず、アンインストールされる。
+    ToUninstall,
+    /// 設定ファイルに記載されていないが、アンインストールされずに残される。
+    Unmanaged,
+    /// 依存関係を解決できなかったので、必要かどうかや何に依存しているかがわからない。
+    Unresolved,
+}
+
+impl NodeStatus {
+    const ALL: [NodeStatus; 5] = [
+        NodeStatus::Installed,
+        NodeStatus::ToInstall,
+        NodeStatus::ToUninstall,
+        NodeStatus::Unmanaged,
+        NodeStatus::Unresolved,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            NodeStatus::Installed => "installed",
+            NodeStatus::ToInstall => "to install",
+            NodeStatus::ToUninstall => "to uninstall",
+            NodeStatus::Unmanaged => "unmanaged",
+            NodeStatus::Unresolved => "unresolved",
+        }
+    }
+
+    /// Mermaid のクラス名。
+    fn class(self) -> &'static str {
+        match self {
+            NodeStatus::Installed => "installed",
+            NodeStatus::ToInstall => "toInstall",
+            NodeStatus::ToUninstall => "toUninstall",
+            NodeStatus::Unmanaged => "unmanaged",
+            NodeStatus::Unresolved => "unresolved",
+        }
+    }
+
+    fn fill_color(self) -> &'static str {
+        match self {
+            NodeStatus::Installed => "#c8e6c9",
+            NodeStatus::ToInstall => "#bbdefb",
+            NodeStatus::ToUninstall => "#ffcdd2",
+            NodeStatus::Unmanaged => "#eeeeee",
+            NodeStatus::Unresolved => "#fff9c4",
+        }
+    }
+
+    /// 確かでない状態なので、枠を破線で描く。
+    fn dashed(self) -> bool {
+        self == NodeStatus::Unresolved
+    }
+}
+
+/// 状態を付けた依存関係のグラフ。
+///
+/// ドキュメントに載せたり、環境が大きくなっている原因を調べたりするために使う。出力が実行ごとに
+/// 変わらないよう、ノードと辺は名前順に並べる。
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphReport {
+    pub nodes: Vec<GraphNode>,
+    /// 直接の依存関係。
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphNode {
+    pub app: ScoopApp,
+    pub status: NodeStatus,
+    /// 設定ファイルに直接記載されているかどうか。
+    pub explicit: bool,
+    /// 全ユーザー向けに必要、またはインストールされているかどうか。
+    pub global: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphEdge {
+    pub from: ScoopApp,
+    pub to: ScoopApp,
+}
+
+impl GraphReport {
+    pub fn new(
+        required: &RequiredThings,
+        installed: &InstalledThings,
+        to_uninstall: &ThingsToUninstall,
+    ) -> Self {
+        let graph = DependencyGraph::new(required);
+
+        // 必要なアプリケーションと、それが必要なスコープ (ユーザー単位, global)
+        let mut scopes: HashMap<&ScoopApp, (bool, bool)> = HashMap::new();
+        for app in &required.user_apps {
+            scopes.entry(app).or_default().0 = true;
+        }
+        for app in &required.global_apps {
+            scopes.entry(app).or_default().1 = true;
+        }
+        for spec in required.app_specs.values() {
+            let scope = scopes.entry(&spec.app).or_default();
+            if spec.global {
+                scope.1 = true;
+            } else {
+                scope.0 = true;
+            }
+        }
+
+        let mut nodes: BTreeMap<String, GraphNode> = BTreeMap::new();
+        for (app, (user, global)) in scopes {
+            // global に必要なアプリケーションは、ユーザー単位のものがあっても満たされない
+            let satisfied = (!user || installed.contains(app))
+                && (!global || installed.get(app, true).is_some());
+            let status = if satisfied {
+                NodeStatus::Installed
+            } else {
+                NodeStatus::ToInstall
+            };
+            nodes.insert(
+                app.to_string(),
+                GraphNode {
+                    app: app.clone(),
+                    status,
+                    explicit: required.app_specs.contains_key(app),
+                    global,
+                },
+            );
+        }
+
+        for (app, global, _) in installed.apps() {
+            let removed = to_uninstall
+                .scoop_apps
+                .iter()
+                .any(|spec| spec.app == *app && spec.global == global);
+            let status = if removed {
+                NodeStatus::ToUninstall
+            } else {
+                NodeStatus::Unmanaged
+            };
+
+            let node = nodes.entry(app.to_string()).or_insert_with(|| GraphNode {
+                app: app.clone(),
+                status,
+                explicit: false,
+                global,
+            });
+            // 両方のスコープにインストールされていれば、アンインストールされる方を優先して表示する
+            if matches!(node.status, NodeStatus::Unmanaged) && removed {
+                node.status = status;
+            }
+        }
+
+        // 依存関係を解決できなかったアプリケーションは、インストールされるかどうかもわからないので、
+        // 必要なものとしての状態より優先して表示する
+        for failure in &required.unresolved {
+            let node = nodes
+                .entry(failure.app.to_string())
+                .or_insert_with(|| GraphNode {
+                    app: failure.app.clone(),
+                    status: NodeStatus::Unresolved,
+                    explicit: false,
+                    global: false,
+                });
+            if matches!(node.status, NodeStatus::Installed | NodeStatus::ToInstall) {
+                node.status = NodeStatus::Unresolved;
+            }
+        }
+
+        // 依存関係は解決できなかったものも含めてノードになっているので、この確認で落ちる辺はない
+        // はずだが、JSON にノードのない辺を出さないよう念のため確認する
+        let edges = nodes
+            .values()
+            .flat_map(|node| {
+                graph
+                    .dependencies_of(&node.app)
+                    .iter()
+                    .filter(|dep| nodes.contains_key(&dep.to_string()))
+                    .map(|dep| GraphEdge {
+                        from: node.app.clone(),
+                        to: dep.clone(),
+                    })
+            })
+            .collect();
+
+        Self {
+            nodes: nodes.into_values().collect(),
+            edges,
+        }
+    }
+
+    /// Graphviz の DOT 形式で出力します。
+    pub fn to_dot(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let dashed = |status: NodeStatus| {
+            if status.dashed() {
+                ", style=\"rounded,filled,dashed\""
+            } else {
+                ""
+            }
+        };
+
+        let mut dot = String::new();
+        writeln!(dot, "digraph dependencies {{").unwrap();
+        writeln!(dot, "    rankdir=LR;").unwrap();
+        writeln!(dot, "    node [shape=box, style=\"rounded,filled\"];").unwrap();
+        writeln!(dot).unwrap();
+
+        for node in &self.nodes {
+            // 設定ファイルに記載されたものは枠を太くする
+            let penwidth = if node.explicit { 2 } else { 1 };
+            let label = if node.global {
+                format!("{} [global]", node.app)
+            } else {
+                node.app.to_string()
+            };
+            writeln!(
+                dot,
+                "    {} [label={}, fillcolor=\"{}\", penwidth={penwidth}{}];",
+                quote(&node.app.to_string()),
+                quote(&label),
+                node.status.fill_color(),
+                dashed(node.status),
+            )
+            .unwrap();
+        }
+        writeln!(dot).unwrap();
+
+        for edge in self.drawable_edges() {
+            writeln!(
+                dot,
+                "    {} -> {};",
+                quote(&edge.from.to_string()),
+                quote(&edge.to.to_string())
+            )
+            .unwrap();
+        }
+        writeln!(dot).unwrap();
+
+        writeln!(dot, "    subgraph cluster_legend {{").unwrap();
+        writeln!(dot, "        label=\"Legend\";").unwrap();
+        for status in NodeStatus::ALL {
+            writeln!(
+                dot,
+                "        \"legend_{}\" [label=\"{}\", fillcolor=\"{}\"{}];",
+                status.class(),
+                status.name(),
+                status.fill_color(),
+                dashed(status),
+            )
+            .unwrap();
+        }
+        writeln!(dot, "    }}").unwrap();
+        writeln!(dot, "}}").unwrap();
+
+        dot
+    }
+
+    /// Mermaid のフローチャート形式で出力します。
+    pub fn to_mermaid(&self) -> String {
+        // Mermaid のノード ID には `/` を使えないので、連番の ID を振ってラベルに名前を書く
+        let ids: HashMap<&ScoopApp, String> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (&node.app, format!("n{i}")))
+            .collect();
+
+        let mut mermaid = String::new();
+        writeln!(mermaid, "flowchart LR").unwrap();
+        for node in &self.nodes {
+            let label = if node.global {
+                format!("{} [global]", node.app)
+            } else {
+                node.app.to_string()
+            };
+            writeln!(
+                mermaid,
+                "    {}[\"{}\"]:::{}",
+                ids[&node.app],
+                label.replace('"', "#quot;"),
+                node.status.class()
+            )
+            .unwrap();
+        }
+        for edge in self.drawable_edges() {
+            writeln!(mermaid, "    {} --> {}", ids[&edge.from], ids[&edge.to]).unwrap();
+        }
+
+        let explicit = self
+            .nodes
+            .iter()
+            .filter(|node| node.explicit)
+            .map(|node| &ids[&node.app])
+            .join(",");
+        if !explicit.is_empty() {
+            writeln!(mermaid, "    class {explicit} explicit").unwrap();
+        }
+        for status in NodeStatus::ALL {
+            let dash = if status.dashed() {
+                ",stroke-dasharray:5 5"
+            } else {
+                ""
+            };
+            writeln!(
+                mermaid,
+                "    classDef {} fill:{}{dash}",
+                status.class(),
+                status.fill_color()
+            )
+            .unwrap();
+        }
+        writeln!(mermaid, "    classDef explicit stroke-width:3px").unwrap();
+
+        mermaid
+    }
+
+    /// 両端がノードとして描かれる辺を返します。`new` で作ったものは常にそうなっているが、フィール
+    /// ドは公開しているので描画時にも確認する。
+    fn drawable_edges(&self) -> impl Iterator<Item = &GraphEdge> {
+        let apps: HashSet<&ScoopApp> = self.nodes.iter().map(|node| &node.app).collect();
+        self.edges
+            .iter()
+            .filter(move |edge| apps.contains(&edge.from) && apps.contains(&edge.to))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .into_diagnostic()
+            .wrap_err("failed to serialize graph as JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::config::{AppSpec, Arch};
+    use crate::test_util::{app, config, required};
+    use crate::{InstalledApp, ResolutionFailure};
+
+    fn path(ids: &[&str]) -> (bool, Vec<ScoopApp>) {
+        (false, ids.iter().map(|id| app(id)).collect())
+    }
+
+    #[test]
+    fn removes_indirect_edges() {
+        let graph = DependencyGraph::new(&required(
+            &config("scoop_apps: [main/a]"),
+            &[
+                ("main/a", &["main/a", "main/b", "main/c"]),
+                ("main/b", &["main/b", "main/c"]),
+                ("main/c", &["main/c"]),
+            ],
+        ));
+
+        assert_eq!(graph.dependencies_of(&app("main/a")), [app("main/b")]);
+        assert_eq!(graph.dependencies_of(&app("main/b")), [app("main/c")]);
+        assert_eq!(graph.dependencies_of(&app("main/c")), []);
+    }
+
+    #[test]
+    fn keeps_edges_through_cycles() {
+        // a と b が循環していても、c への辺はどちらかに残る
+        let graph = DependencyGraph::new(&required(
+            &config("scoop_apps: [main/a]"),
+            &[
+                ("main/a", &["main/a", "main/b", "main/c"]),
+                ("main/b", &["main/b", "main/a", "main/c"]),
+                ("main/c", &["main/c"]),
+            ],
+        ));
+
+        assert_eq!(
+            graph.dependencies_of(&app("main/a")),
+            [app("main/b"), app("main/c")]
+        );
+        let (paths, _) = graph.paths_to(&app("main/c"), 10);
+        assert_eq!(
+            paths,
+            [
+                path(&["main/a", "main/b", "main/c"]),
+                path(&["main/a", "main/c"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_paths_up_to_limit() {
+        let graph = DependencyGraph::new(&required(
+            &config("scoop_apps: [main/a, main/e]"),
+            &[
+                ("main/a", &["main/a", "main/b", "main/c", "main/d"]),
+                ("main/b", &["main/b", "main/d"]),
+                ("main/c", &["main/c", "main/d"]),
+                ("main/d", &["main/d"]),
+                ("main/e", &["main/e"]),
+            ],
+        ));
+
+        let (paths, truncated) = graph.paths_to(&app("main/d"), 10);
+        assert_eq!(
+            paths,
+            [
+                path(&["main/a", "main/b", "main/d"]),
+                path(&["main/a", "main/c", "main/d"]),
+            ]
+        );
+        assert!(!truncated);
+
+        let (paths, truncated) = graph.paths_to(&app("main/d"), 1);
+        assert_eq!(paths, [path(&["main/a", "main/b", "main/d"])]);
+        assert!(truncated);
+
+        // 設定ファイルに記載されたアプリケーション自身は、それだけの経路になる
+        let (paths, _) = graph.paths_to(&app("main/e"), 10);
+        assert_eq!(paths, [path(&["main/e"])]);
+    }
+
+    /// main/a (main/b に依存) を記載し、main/a と extras/old がインストールされている状態のグラフ
+    /// を作ります。
+    fn report(resolved: &[(&str, &[&str])]) -> GraphReport {
+        report_with(&required(&config("scoop_apps: [main/a]"), resolved))
+    }
+
+    fn report_with(required: &RequiredThings) -> GraphReport {
+        let installed_app = InstalledApp {
+            version: "1.0".to_string(),
+            arch: Arch::X64,
+            held: false,
+        };
+        let installed = InstalledThings {
+            scoop_buckets: Vec::new(),
+            scoop_apps: HashMap::from([
+                (app("main/a"), installed_app.clone()),
+                (app("extras/old"), installed_app),
+            ]),
+            global_scoop_apps: HashMap::new(),
+        };
+        let to_uninstall = ThingsToUninstall {
+            scoop_buckets: HashSet::new(),
+            scoop_apps: HashSet::from([AppSpec::unpinned(app("extras/old"))]),
+            kept_scoop_buckets: HashSet::new(),
+            kept_scoop_apps: HashSet::new(),
+        };
+
+        GraphReport::new(required, &installed, &to_uninstall)
+    }
+
+    const RESOLVED: &[(&str, &[&str])] =
+        &[("main/a", &["main/a", "main/b"]), ("main/b", &["main/b"])];
+
+    #[test]
+    fn renders_dot() {
+        let dot = report(RESOLVED).to_dot();
+
+        assert!(
+            dot.contains(
+                r##""extras/old" [label="extras/old", fillcolor="#ffcdd2", penwidth=1];"##
+            )
+        );
+        assert!(dot.contains(r##""main/a" [label="main/a", fillcolor="#c8e6c9", penwidth=2];"##));
+        assert!(dot.contains(r##""main/b" [label="main/b", fillcolor="#bbdefb", penwidth=1];"##));
+        assert!(dot.contains(r#""main/a" -> "main/b";"#));
+    }
+
+    #[test]
+    fn renders_mermaid() {
+        let mermaid = report(RESOLVED).to_mermaid();
+
+        // ノードは名前順に連番の ID が振られる
+        assert!(mermaid.contains(r#"n0["extras/old"]:::toUninstall"#));
+        assert!(mermaid.contains(r#"n1["main/a"]:::installed"#));
+        assert!(mermaid.contains(r#"n2["main/b"]:::toInstall"#));
+        assert!(mermaid.contains("n1 --> n2"));
+        assert!(mermaid.contains("class n1 explicit"));
+    }
+
+    #[test]
+    fn renders_unresolved_dependencies() {
+        // main/b の依存関係は解決できなかったので、状態のわからないノードとして破線で描く
+        let mut required = required(
+            &config("scoop_apps: [main/a]"),
+            &[("main/a", &["main/a", "main/b"])],
+        );
+        required.unresolved.push(ResolutionFailure {
+            app: app("main/b"),
+            optional: false,
+            error: "Couldn't find manifest for main/b".to_string(),
+        });
+        let report = report_with(&required);
+
+        let b = report.nodes.iter().find(|node| node.app == app("main/b"));
+        assert_eq!(b.unwrap().status, NodeStatus::Unresolved);
+        let dot = report.to_dot();
+        assert!(dot.contains(
+            r##""main/b" [label="main/b", fillcolor="#fff9c4", penwidth=1, style="rounded,filled,dashed"];"##
+        ));
+        assert!(dot.contains(r#""main/a" -> "main/b";"#));
+        let mermaid = report.to_mermaid();
+        assert!(mermaid.contains(r#"n2["main/b"]:::unresolved"#));
+        assert!(mermaid.contains("n1 --> n2"));
+        assert!(mermaid.contains("classDef unresolved fill:#fff9c4,stroke-dasharray:5 5"));
+
+        // 外から辺を加えても描画で失敗しない
+        let mut report = report;
+        report.edges.push(GraphEdge {
+            from: app("main/a"),
+            to: app("main/missing"),
+        });
+        assert!(!report.to_dot().contains("main/missing"));
+        assert!(!report.to_mermaid().contains("main/missing"));
+    }
+
+    #[test]
+    fn escapes_labels() {
+        let weird = ScoopApp {
+            name: r#"a"b\c"#.to_string(),
+            bucket_name: "main".to_string(),
+        };
+        let report = GraphReport {
+            nodes: vec![GraphNode {
+                app: weird,
+                status: NodeStatus::ToInstall,
+                explicit: true,
+                global: true,
+            }],
+            edges: Vec::new(),
+        };
+
+        assert!(
+            report
+                .to_dot()
+                .contains(r#"[label="main/a\"b\\c [global]""#)
+        );
+        assert!(
+            report
+                .to_mermaid()
+                .contains(r#"n0["main/a#quot;b\c [global]"]:::toInstall"#)
+        );
+    }
+}
@@ -1,282 +1,1183 @@
 use std::{
     collections::{HashMap, HashSet, VecDeque},
-    fmt,
-    fs::File,
-    io::{self, BufReader, Write},
+    env, fmt, fs,
+    io::{self, Write},
+    iter, mem,
+    num::NonZeroUsize,
     path::Path,
+    process::ExitCode,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
 };
 
+use clap::Parser;
 use colored::*;
 use itertools::Itertools;
 use miette::{IntoDiagnostic, Result, WrapErr, bail, miette};
-use serde::Deserialize;
-
-use crate::client::{ExecResult, ScoopClient};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::Backend;
+use crate::cache::DependencyCache;
+use crate::cli::{Cli, Command, GraphFormat, OutputFormat, PlanOptions, ResolveOptions, Resolver};
+use crate::client::ScoopClient;
+use crate::config::{
+    AppSpec, Arch, Config, ConfigContext, IgnoreRules, ResolutionFailurePolicy, ScoopApp,
+    ScoopBucket, UpdatePolicy, load_config, read_config_from_file,
+};
+use crate::diagnostic::ConfigProblems;
+use crate::fake::{FAKE_STATE_ENV, FakeBackend};
+use crate::graph::{DependencyGraph, GraphReport};
+use crate::manifest::ManifestResolver;
+use crate::plan_file::PlanFile;
+use crate::preflight::{
+    BucketIssue, check_declared_buckets, check_dependencies, check_installed_sources,
+    check_resolved_buckets, report_bucket_issues, report_dependency_issues,
+};
+use crate::report::PlanReport;
 
+mod backend;
+mod cache;
+mod cli;
 mod client;
+mod config;
+mod diagnostic;
+mod fake;
+mod graph;
+mod manifest;
+mod plan_file;
+mod preflight;
+mod report;
+#[cfg(test)]
+mod test_util;
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct Config {
-    pub scoop_buckets: Vec<ScoopBucket>,
-    pub scoop_apps: Vec<ScoopApp>,
+fn make_label(title: &str) -> impl fmt::Display {
+    format!("{title:>10}").green().bold()
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
-pub struct ScoopBucket {
-    pub name: String,
-    pub source: String,
+fn make_sublabel(title: &str) -> impl fmt::Display {
+    format!("{title:>10}").cyan().bold()
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct ScoopApp {
-    pub name: String,
-    pub bucket_name: String,
+fn make_warning_label() -> impl fmt::Display {
+    format!("{:>10}", "Warning").yellow().bold()
 }
 
-// ScoopApp は {bucket_name}/{name} の形式で表示する
-impl fmt::Display for ScoopApp {
-    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
-        write!(b, "{}/{}", self.bucket_name, self.name)
-    }
+fn format_item_add(kind: &str, name: impl fmt::Display) -> String {
+    format!("{:>8} {}", kind.green(), name)
 }
 
-impl<'de> Deserialize<'de> for ScoopApp {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let name = String::deserialize(deserializer)?;
-        let parts: Vec<&str> = name.splitn(2, '/').collect();
-        let Ok([bucket_name, name]): Result<[_; 2], _> = parts.try_into() else {
-            return Err(serde::de::Error::custom("invalid format"));
-        };
+fn format_item_remove(kind: &str, name: impl fmt::Display) -> String {
+    format!("{:>8} {}", kind.red(), name)
+}
 
-        Ok(ScoopApp {
-            name: name.to_string(),
-            bucket_name: bucket_name.to_string(),
-        })
-    }
+fn format_item_keep(kind: &str, name: impl fmt::Display) -> String {
+    format!(
+        "{:>8} {} ({})",
+        kind.dimmed(),
+        name,
+        "unmanaged, kept".dimmed()
+    )
 }
 
-fn make_label(title: &str) -> impl fmt::Display {
-    format!("{title:>10}").green().bold()
+fn format_item_change(kind: &str, name: impl fmt::Display, from: &str, to: &str) -> String {
+    format!("{:>8} {} ({} -> {})", kind.yellow(), name, from, to)
 }
 
-fn make_sublabel(title: &str) -> impl fmt::Display {
-    format!("{title:>10}").cyan().bold()
+fn format_item_upgrade(kind: &str, name: impl fmt::Display, from: &str, to: &str) -> String {
+    format!("{:>8} {} ({} -> {})", kind.blue(), name, from, to)
 }
 
-fn format_item_add(kind: &str, name: impl fmt::Display) -> String {
-    format!("{:>8} {}", kind.green(), name)
+fn format_item_status(kind: &str, name: impl fmt::Display, installed: bool) -> String {
+    let status = if installed {
+        "installed".green()
+    } else {
+        "missing".red()
+    };
+    format!("{:>8} {:<40} {}", kind.cyan(), name.to_string(), status)
 }
 
-fn format_item_remove(kind: &str, name: impl fmt::Display) -> String {
-    format!("{:>8} {}", kind.red(), name)
+/// 計画の表示用に、global なアプリケーションにはその旨を付けて返します。
+fn describe_scope(app: &ScoopApp, global: bool) -> String {
+    if global {
+        format!("{app} [global]")
+    } else {
+        app.to_string()
+    }
+}
+
+/// `apply --dry-run` で未適用の変更が残っているときの終了コード。
+const EXIT_CHANGES_PENDING: u8 = 2;
+
+fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+    let context = ConfigContext::current(cli.profiles);
+    let command = cli.command.unwrap_or(Command::Apply {
+        yes: false,
+        dry_run: false,
+        options: PlanOptions::default(),
+    });
+
+    // 設定ファイルの検証には scoop を使わないので、バックエンドを開く前に済ませる
+    if let Command::Validate = command {
+        validate(&cli.config, &context)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut client = open_backend()?;
+    let result = run(&mut *client, &cli.config, &context, command, &cli.resolve);
+    client.finish();
+
+    result
+}
+
+fn run(
+    client: &mut dyn Backend,
+    config_path: &Path,
+    context: &ConfigContext,
+    command: Command,
+    resolve: &ResolveOptions,
+) -> Result<ExitCode> {
+    match command {
+        Command::Plan {
+            format,
+            out,
+            options,
+        } => plan(
+            client,
+            config_path,
+            context,
+            format,
+            out.as_deref(),
+            &options,
+            resolve,
+        )?,
+        Command::Apply {
+            yes,
+            dry_run,
+            options,
+        } => {
+            return apply(
+                client,
+                config_path,
+                context,
+                yes,
+                dry_run,
+                &options,
+                resolve,
+            );
+        }
+        Command::ApplyPlan { plan, yes } => apply_plan(client, &plan, yes)?,
+        Command::Status => status(client, config_path, context, resolve)?,
+        Command::Validate => unreachable!("validate does not use the backend"),
+        Command::Why { app, options } => {
+            why(client, config_path, context, &app, &options, resolve)?
+        }
+        Command::Graph { format, output } => graph(
+            client,
+            config_path,
+            context,
+            format,
+            output.as_deref(),
+            resolve,
+        )?,
+        Command::Export { output } => export(client, output.as_deref())?,
+    }
+
+    Ok(ExitCode::SUCCESS)
 }
 
-fn main() -> Result<()> {
-    let config = read_config_from_file("app-requirements.yaml")?;
-    let mut client = ScoopClient::new().wrap_err("failed to initialize scoop client")?;
+/// 設定ファイルと現在の状態から計算した実行計画。
+struct Plan {
+    required: RequiredThings,
+    installed: InstalledThings,
+    to_uninstall: ThingsToUninstall,
+    to_install: ThingsToInstall,
+}
+
+/// 設定ファイルと現在の状態から、アンインストール・インストールする項目を計算します。
+fn compute_plan(
+    client: &mut dyn Backend,
+    config: &Config,
+    options: &PlanOptions,
+    resolve: &ResolveOptions,
+) -> Result<Plan> {
+    let additive = config.additive || options.additive;
+    let policy = options
+        .on_resolution_failure
+        .or(config.on_resolution_failure)
+        .unwrap_or_default();
+
+    // 宣言されていないバケットのアプリケーションは解決できないので、先に確認しておく
+    report_bucket_issues(&check_declared_buckets(config, additive))?;
+    let required = get_required_things(client, config, policy, resolve)
+        .wrap_err("failed to resolve dependencies")?;
+    report_bucket_issues(&check_resolved_buckets(config, &required, additive))?;
 
-    let required =
-        get_required_things(&mut client, &config).wrap_err("failed to resolve dependencies")?;
     let installed =
-        get_installed_things(&mut client).wrap_err("failed to get installed applications")?;
-    let to_uninstall = compute_things_to_uninstall(&installed, &required);
-    let to_install = compute_things_to_install(&installed, required);
+        get_installed_things(client).wrap_err("failed to get installed applications")?;
+    let outdated = get_outdated_apps(client, config, options, &required, &installed)
+        .wrap_err("failed to check for outdated applications")?;
+    let mut to_uninstall = compute_things_to_uninstall(&installed, &required, &config.ignore);
+    if additive {
+        to_uninstall.keep_all();
+    }
+    let to_install = compute_things_to_install(&installed, &required, &outdated);
+
+    // 計画を表示する前に、実行しても失敗するとわかっている問題を報告する
+    let mut issues = check_dependencies(&required);
+    issues.extend(check_installed_sources(
+        &installed,
+        &to_uninstall,
+        &to_install,
+        additive,
+    ));
+    report_dependency_issues(issues)?;
+
+    Ok(Plan {
+        required,
+        installed,
+        to_uninstall,
+        to_install,
+    })
+}
+
+/// 実行計画を表示します。変更がなければ `false` を返します。
+fn describe_plan(to_uninstall: &ThingsToUninstall, to_install: &ThingsToInstall) -> bool {
+    to_uninstall.describe_kept();
 
     if to_uninstall.is_empty() && to_install.is_empty() {
         println!();
         println!("{}", "Everything is up to date!".green().bold());
-        return Ok(());
+        return false;
     }
 
     to_uninstall.describe_plan();
     to_install.describe_plan();
 
+    true
+}
+
+/// 実行計画に従ってアンインストールとインストールを行います。
+fn execute_plan(
+    client: &mut dyn Backend,
+    to_uninstall: &ThingsToUninstall,
+    to_install: &ThingsToInstall,
+) -> Result<()> {
+    if !to_uninstall.is_empty() {
+        println!("{} items", make_label("Uninstalling"));
+        uninstall_apps(client, &to_uninstall.scoop_apps)?;
+        uninstall_buckets(client, &to_uninstall.scoop_buckets)?;
+    }
+
+    if !to_install.is_empty() {
+        println!("{} items", make_label("Installing"));
+        install_buckets(client, &to_install.scoop_buckets)?;
+        install_apps(client, &to_install.scoop_apps)?;
+        reinstall_apps(client, &to_install.scoop_apps_to_reinstall)?;
+        // 固定されたアプリケーションは更新されないので、固定の解除は更新より先に行う
+        unhold_apps(client, &to_install.scoop_apps_to_unhold)?;
+        upgrade_apps(client, &to_install.scoop_apps_to_upgrade)?;
+        hold_apps(client, &to_install.scoop_apps_to_hold)?;
+    }
+
+    println!("{}", "Operation completed successfully!".green().bold());
+
+    Ok(())
+}
+
+fn plan(
+    client: &mut dyn Backend,
+    config_path: &Path,
+    context: &ConfigContext,
+    format: OutputFormat,
+    out: Option<&Path>,
+    options: &PlanOptions,
+    resolve: &ResolveOptions,
+) -> Result<()> {
+    let config = read_config_from_file(config_path, context)?;
+    let plan = compute_plan(client, &config, options, resolve)?;
+
+    if let Some(out) = out {
+        let Plan {
+            installed,
+            to_uninstall,
+            to_install,
+            ..
+        } = &plan;
+        PlanFile::new(installed.clone(), to_uninstall.clone(), to_install.clone())
+            .write_to_file(out)?;
+        eprintln!("{} plan to {}", make_label("Saved"), out.display());
+    }
+
+    match format {
+        OutputFormat::Text => {
+            describe_plan(&plan.to_uninstall, &plan.to_install);
+        }
+        OutputFormat::Json => {
+            let report = PlanReport::new(
+                &config,
+                &plan.required,
+                &plan.to_uninstall,
+                &plan.to_install,
+            );
+            println!("{}", report.to_json()?);
+        }
+        OutputFormat::Yaml => {
+            let report = PlanReport::new(
+                &config,
+                &plan.required,
+                &plan.to_uninstall,
+                &plan.to_install,
+            );
+            print!("{}", report.to_yaml()?);
+        }
+    }
+
+    Ok(())
+}
+
+fn apply(
+    client: &mut dyn Backend,
+    config_path: &Path,
+    context: &ConfigContext,
+    yes: bool,
+    dry_run: bool,
+    options: &PlanOptions,
+    resolve: &ResolveOptions,
+) -> Result<ExitCode> {
+    let config = read_config_from_file(config_path, context)?;
+    let plan = compute_plan(client, &config, options, resolve)?;
+
+    if !describe_plan(&plan.to_uninstall, &plan.to_install) {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if dry_run {
+        println!();
+        println!("{}", "Dry run: no changes were made.".yellow());
+        return Ok(ExitCode::from(EXIT_CHANGES_PENDING));
+    }
+
+    if !yes && !confirm()? {
+        println!("{}", "Operation cancelled.".yellow());
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    execute_plan(client, &plan.to_uninstall, &plan.to_install)?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn apply_plan(client: &mut dyn Backend, plan_path: &Path, yes: bool) -> Result<()> {
+    let saved = PlanFile::read_from_file(plan_path)?;
+
+    let installed =
+        get_installed_things(client).wrap_err("failed to get installed applications")?;
+    saved
+        .ensure_up_to_date(&installed)
+        .wrap_err_with(|| miette!("refusing to apply plan {path}", path = plan_path.display()))?;
+
+    if !describe_plan(&saved.to_uninstall, &saved.to_install) {
+        return Ok(());
+    }
+
+    if !yes && !confirm()? {
+        println!("{}", "Operation cancelled.".yellow());
+        return Ok(());
+    }
+
+    execute_plan(client, &saved.to_uninstall, &saved.to_install)
+}
+
+/// 変更を適用してよいかをユーザーに確認します。
+fn confirm() -> Result<bool> {
     println!();
     print!("Do you want to proceed? {} ", "[y/N]".cyan());
     io::stdout().flush().into_diagnostic()?;
     let mut input = String::new();
     io::stdin().read_line(&mut input).into_diagnostic()?;
 
-    if !matches!(input.trim().to_lowercase().as_str(), "y" | "yes" | "Y") {
-        println!("{}", "Operation cancelled.".yellow());
-        return Ok(());
+    Ok(matches!(
+        input.trim().to_lowercase().as_str(),
+        "y" | "yes" | "Y"
+    ))
+}
+
+fn status(
+    client: &mut dyn Backend,
+    config_path: &Path,
+    context: &ConfigContext,
+    resolve: &ResolveOptions,
+) -> Result<()> {
+    let config = read_config_from_file(config_path, context)?;
+    let required = get_required_things(
+        client,
+        &config,
+        config.on_resolution_failure.unwrap_or_default(),
+        resolve,
+    )
+    .wrap_err("failed to resolve dependencies")?;
+    let installed =
+        get_installed_things(client).wrap_err("failed to get installed applications")?;
+
+    println!();
+    println!("{}", "Required items".bold());
+    for bucket in &required.scoop_buckets {
+        let is_installed = installed.scoop_buckets.contains(bucket);
+        println!(
+            "{}",
+            format_item_status("bucket", &bucket.name, is_installed)
+        );
+    }
+    for app in required
+        .scoop_apps
+        .keys()
+        .sorted_by_key(|app| app.to_string())
+    {
+        // global に必要なアプリケーションは、ユーザー単位のものがあっても満たされない
+        let global = required.requires(app, true);
+        let is_installed = if global {
+            installed.get(app, true).is_some()
+        } else {
+            installed.contains(app)
+        };
+        println!(
+            "{}",
+            format_item_status("app", describe_scope(app, global), is_installed)
+        );
     }
 
-    if !to_uninstall.is_empty() {
-        println!("{} items", make_label("Uninstalling"));
-        uninstall_apps(&mut client, &to_uninstall.scoop_apps)?;
-        uninstall_buckets(&mut client, &to_uninstall.scoop_buckets)?;
+    let mut unmanaged = compute_things_to_uninstall(&installed, &required, &config.ignore);
+    if config.additive {
+        unmanaged.keep_all();
+    }
+    if !unmanaged.is_empty() || !unmanaged.is_nothing_kept() {
+        println!();
+        println!("{}", "Items not in the configuration".bold());
+        for bucket in &unmanaged.scoop_buckets {
+            println!("{}", format_item_remove("bucket", &bucket.name));
+        }
+        for app in &unmanaged.scoop_apps {
+            println!("{}", format_item_remove("app", app.describe()));
+        }
+        for bucket in &unmanaged.kept_scoop_buckets {
+            println!("{}", format_item_keep("bucket", &bucket.name));
+        }
+        for app in &unmanaged.kept_scoop_apps {
+            println!("{}", format_item_keep("app", app.describe()));
+        }
     }
 
-    if !to_install.is_empty() {
-        println!("{} items", make_label("Installing"));
-        install_buckets(&mut client, &to_install.scoop_buckets)?;
-        install_apps(&mut client, &to_install.scoop_apps)?;
+    Ok(())
+}
+
+/// `why` で表示する経路の数の上限。
+const MAX_WHY_PATHS: usize = 20;
+
+fn why(
+    client: &mut dyn Backend,
+    config_path: &Path,
+    context: &ConfigContext,
+    app: &str,
+    options: &PlanOptions,
+    resolve: &ResolveOptions,
+) -> Result<()> {
+    let config = read_config_from_file(config_path, context)?;
+    let policy = options
+        .on_resolution_failure
+        .or(config.on_resolution_failure)
+        .unwrap_or_default();
+    let required = get_required_things(client, &config, policy, resolve)
+        .wrap_err("failed to resolve dependencies")?;
+    let installed =
+        get_installed_things(client).wrap_err("failed to get installed applications")?;
+    // 残すかどうかは apply と同じ判定に従う
+    let mut to_uninstall = compute_things_to_uninstall(&installed, &required, &config.ignore);
+    if config.additive || options.additive {
+        to_uninstall.keep_all();
+    }
+    let graph = DependencyGraph::new(&required);
+
+    // バケットが省略されていれば、必要なものやインストール済みのものから同じ名前のものを探す
+    let known = required
+        .scoop_apps
+        .iter()
+        .flat_map(|(app, deps)| iter::once(app).chain(deps))
+        .chain(required.app_specs.keys())
+        .chain(installed.apps().map(|(app, _, _)| app));
+    let targets = match app.parse::<ScoopApp>() {
+        Ok(app) => known
+            .filter(|known| **known == app)
+            .unique()
+            .cloned()
+            .collect_vec(),
+        Err(_) => known
+            .filter(|known| known.name == app)
+            .unique()
+            .cloned()
+            .sorted_by_key(|app| app.to_string())
+            .collect(),
+    };
+    if targets.is_empty() {
+        bail!("{app} is neither required nor installed");
     }
 
-    println!("{}", "Operation completed successfully!".green().bold());
+    for target in &targets {
+        let kind = if let Some(spec) = required.app_specs.get(target) {
+            let kind = "listed in the configuration".green();
+            if spec.global {
+                format!("{kind} [global]")
+            } else {
+                kind.to_string()
+            }
+        } else if required.requires(target, false) || required.requires(target, true) {
+            "dependency of listed apps".cyan().to_string()
+        } else if installed.contains(target) {
+            let kind = "installed but unmanaged".yellow();
+            if to_uninstall
+                .scoop_apps
+                .iter()
+                .any(|spec| spec.app == *target)
+            {
+                format!("{kind} (will be uninstalled)")
+            } else {
+                format!("{kind} (kept)")
+            }
+        } else {
+            "neither required nor installed".dimmed().to_string()
+        };
+
+        println!();
+        println!("{}: {kind}", target.to_string().bold());
+        if required.unresolved.iter().any(|f| f.app == *target) {
+            println!(
+                "{:>8} its dependencies could not be resolved",
+                "note".yellow()
+            );
+        }
+        let (paths, truncated) = graph.paths_to(target, MAX_WHY_PATHS);
+        for (global, path) in paths {
+            // 記載されたアプリケーション自身だけの経路は見出しと重複するので表示しない
+            if path.len() < 2 {
+                continue;
+            }
+            let (root, rest) = path.split_first().expect("path is never empty");
+            println!(
+                "{:>8} {}",
+                "via".cyan(),
+                iter::once(describe_scope(root, global))
+                    .chain(rest.iter().map(ToString::to_string))
+                    .join(" -> ")
+            );
+        }
+        if truncated {
+            println!(
+                "{:>8} more paths are omitted; showing the first {MAX_WHY_PATHS}",
+                "note".yellow()
+            );
+        }
+    }
 
     Ok(())
 }
 
-fn read_config_from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
-    let path = path.as_ref();
-    let file = File::open(path).into_diagnostic().wrap_err_with(|| {
-        miette!(
-            "failed to read app list from file {path}",
-            path = path.display()
-        )
-    })?;
-    let reader = BufReader::new(file);
+fn graph(
+    client: &mut dyn Backend,
+    config_path: &Path,
+    context: &ConfigContext,
+    format: GraphFormat,
+    output: Option<&Path>,
+    resolve: &ResolveOptions,
+) -> Result<()> {
+    let config = read_config_from_file(config_path, context)?;
+    let required = get_required_things(
+        client,
+        &config,
+        config.on_resolution_failure.unwrap_or_default(),
+        resolve,
+    )
+    .wrap_err("failed to resolve dependencies")?;
+    let installed =
+        get_installed_things(client).wrap_err("failed to get installed applications")?;
+    let mut to_uninstall = compute_things_to_uninstall(&installed, &required, &config.ignore);
+    if config.additive {
+        to_uninstall.keep_all();
+    }
+
+    let report = GraphReport::new(&required, &installed, &to_uninstall);
+    let rendered = match format {
+        GraphFormat::Dot => report.to_dot(),
+        GraphFormat::Mermaid => report.to_mermaid(),
+        GraphFormat::Json => report.to_json()? + "\n",
+    };
+
+    match output {
+        Some(path) => fs::write(path, rendered)
+            .into_diagnostic()
+            .wrap_err_with(|| {
+                miette!(
+                    "failed to write graph to file {path}",
+                    path = path.display()
+                )
+            })?,
+        None => print!("{rendered}"),
+    }
+
+    Ok(())
+}
+
+fn validate(config_path: &Path, context: &ConfigContext) -> Result<()> {
+    let mut loaded = load_config(config_path, context)?;
+
+    // 読み込み中に見つかった問題と合わせて、計画を作成するときと同じ確認の結果も、問題のある項目
+    // の位置と一緒に報告する
+    let mut problems = mem::take(&mut loaded.problems);
+    let config = &loaded.config;
+    for issue in check_declared_buckets(config, config.additive) {
+        let BucketIssue::Undeclared { app, .. } = &issue else {
+            continue;
+        };
+        if !issue.is_error() {
+            eprintln!("{} {issue}", make_warning_label());
+            continue;
+        }
+        problems.extend(loaded.app_diagnostic(app, issue.to_string(), "bucket not declared"));
+    }
+    if !problems.is_empty() {
+        return Err(ConfigProblems { problems }.into());
+    }
+
+    println!(
+        "{} {} ({} buckets, {} apps)",
+        make_label("Valid"),
+        config_path.display(),
+        config.scoop_buckets.len(),
+        config.scoop_apps.len()
+    );
+
+    Ok(())
+}
 
-    serde_yaml::from_reader(reader)
+fn export(client: &mut dyn Backend, output: Option<&Path>) -> Result<()> {
+    let installed =
+        get_installed_things(client).wrap_err("failed to get installed applications")?;
+
+    let config = Config {
+        include: Vec::new(),
+        scoop_buckets: installed.scoop_buckets.clone(),
+        scoop_apps: installed
+            .apps()
+            .sorted_by_key(|(app, global, _)| (app.to_string(), *global))
+            .map(|(app, global, installed)| AppSpec {
+                global,
+                hold: installed.held,
+                ..AppSpec::unpinned(app.clone())
+            })
+            .collect(),
+        default_arch: None,
+        on_resolution_failure: None,
+        update: UpdatePolicy::default(),
+        ignore: IgnoreRules::default(),
+        additive: false,
+        when: None,
+        sections: Vec::new(),
+    };
+    let yaml = serde_yaml::to_string(&config)
         .into_diagnostic()
-        .wrap_err_with(|| {
+        .wrap_err("failed to serialize configuration")?;
+
+    match output {
+        Some(path) => fs::write(path, yaml).into_diagnostic().wrap_err_with(|| {
             miette!(
-                "failed to parse app list from file {path}",
+                "failed to write configuration to file {path}",
                 path = path.display()
             )
-        })
+        })?,
+        None => print!("{yaml}"),
+    }
+
+    Ok(())
+}
+
+/// 使用するバックエンドを開きます。
+// 環境変数で fake バックエンドの状態ファイルが指定されていれば、scoop を実際には呼び出さずにメモリ
+// 上でシミュレートする。Windows 以外の環境での動作確認やデモ用。
+fn open_backend() -> Result<Box<dyn Backend>> {
+    if let Some(path) = env::var_os(FAKE_STATE_ENV) {
+        let fake = FakeBackend::from_file(path).wrap_err("failed to initialize fake backend")?;
+        return Ok(Box::new(fake));
+    }
+
+    let client = ScoopClient::new().wrap_err("failed to initialize scoop client")?;
+    Ok(Box::new(client))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct RequiredThings {
     scoop_buckets: Vec<ScoopBucket>,
     scoop_apps: HashMap<ScoopApp, HashSet<ScoopApp>>,
+    /// 設定ファイルに直接記載されたアプリケーションの指定。
+    app_specs: HashMap<ScoopApp, AppSpec>,
+    /// アーキテクチャを指定していないアプリケーションのアーキテクチャ。
+    default_arch: Option<Arch>,
+    /// ユーザー単位で必要なアプリケーション (ユーザー単位のアプリケーションとその依存関係)。
+    user_apps: HashSet<ScoopApp>,
+    /// 全ユーザー向けに必要なアプリケーション (global なアプリケーションとその依存関係)。
+    global_apps: HashSet<ScoopApp>,
+    /// 依存関係を解決できなかったアプリケーション。
+    unresolved: Vec<ResolutionFailure>,
+    /// 解決できなかったアプリケーションを、インストール済みであれば残すかどうか。
+    keep_unresolved: bool,
 }
 
-fn get_required_things(client: &mut ScoopClient, config: &Config) -> Result<RequiredThings> {
-    println!("{} dependencies", make_label("Loading"));
-    fn get_dependencies_of(client: &mut ScoopClient, app: &ScoopApp) -> Result<HashSet<ScoopApp>> {
-        println!("{} {}", make_sublabel("Resolving"), app);
-        let ExecResult {
-            stdout,
-            stderr,
-            status,
-        } = client.exec(&["depends", &app.to_string()])?;
-
-        if !status.success() || stdout.contains("Couldn't find manifest for") {
-            bail!("failed to get dependencies for {app}: {stderr}",);
-        }
-
-        stdout
-            .lines()
-            .filter(|line| !line.trim().is_empty())
-            .skip(1) // ヘッダー行: Source Name
-            .skip(1) // ヘッダー行: ------ ----
-            .map(|line| {
-                let [bucket_name, name] = line
-                    .split_whitespace()
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .map_err(|_| miette!("invalid dependency line: {line}"))?;
-
-                Ok(ScoopApp {
-                    bucket_name: bucket_name.to_string(),
-                    name: name.to_string(),
-                })
-            })
-            .collect()
+impl RequiredThings {
+    /// 解決できなかったために、インストール済みであれば残すべきアプリケーションかどうかを返しま
+    /// す。
+    fn keeps_unresolved(&self, app: &ScoopApp) -> bool {
+        self.keep_unresolved && self.unresolved.iter().any(|failure| failure.app == *app)
     }
 
-    let mut resolved = HashMap::new();
-    let mut to_resolve = VecDeque::new();
-    to_resolve.extend(config.scoop_apps.to_vec());
+    /// アプリケーションが指定のスコープで必要かどうかを返します。
+    fn requires(&self, app: &ScoopApp, global: bool) -> bool {
+        if global {
+            self.global_apps.contains(app)
+        } else {
+            self.user_apps.contains(app)
+        }
+    }
+
+    /// アプリケーションを指定のスコープにどのようにインストールするべきかを返します。設定ファイル
+    /// に記載されていない (依存関係として必要になった) アプリケーションはバージョンを固定しない。
+    fn spec_of(&self, app: &ScoopApp, global: bool) -> AppSpec {
+        let spec = self
+            .app_specs
+            .get(app)
+            .cloned()
+            .unwrap_or_else(|| AppSpec::unpinned(app.clone()));
+
+        AppSpec {
+            arch: spec.arch.or(self.default_arch),
+            global,
+            ..spec
+        }
+    }
+}
 
-    while let Some(app) = to_resolve.pop_front() {
-        if resolved.contains_key(&app) {
+/// 依存関係をたどって、指定したアプリケーションから到達できるアプリケーションをすべて返します。
+fn collect_reachable<'a>(
+    resolved: &HashMap<ScoopApp, HashSet<ScoopApp>>,
+    roots: impl IntoIterator<Item = &'a ScoopApp>,
+) -> HashSet<ScoopApp> {
+    let mut reachable = HashSet::new();
+    let mut to_visit: VecDeque<_> = roots.into_iter().cloned().collect();
+    while let Some(app) = to_visit.pop_front() {
+        // 解決できなかったアプリケーションは必要なものとして扱わない
+        let Some(deps) = resolved.get(&app) else {
             continue;
+        };
+        if reachable.insert(app) {
+            to_visit.extend(deps.iter().cloned());
         }
+    }
 
-        let dependencies = match get_dependencies_of(client, &app) {
-            Ok(deps) => deps,
-            Err(e) => {
-                println!("{} Skipping due to error: {e}", make_sublabel("Info"));
-                continue;
+    reachable
+}
+
+fn get_required_things(
+    client: &mut dyn Backend,
+    config: &Config,
+    policy: ResolutionFailurePolicy,
+    resolve: &ResolveOptions,
+) -> Result<RequiredThings> {
+    eprintln!("{} dependencies", make_label("Loading"));
+
+    let app_specs: HashMap<_, _> = config
+        .scoop_apps
+        .iter()
+        .map(|spec| (spec.app.clone(), spec.clone()))
+        .collect();
+    let arch = config.default_arch.unwrap_or_else(Arch::host);
+    let arch_of = |app: &ScoopApp| {
+        app_specs
+            .get(app)
+            .and_then(|spec| spec.arch)
+            .unwrap_or(arch)
+    };
+
+    // マニフェストを直接読む場合はプロセス内で十分速く終わるので、セッションは使わない
+    let mut native = match resolve.resolver {
+        Resolver::Scoop => None,
+        Resolver::Native => {
+            let resolver =
+                ManifestResolver::open().wrap_err("failed to open the local bucket directories")?;
+            Some(resolver)
+        }
+    };
+    let mut cache = if resolve.no_cache || !client.caches_dependencies() {
+        None
+    } else {
+        DependencyCache::open(resolve.resolver, arch).unwrap_or_else(|e| {
+            eprintln!(
+                "{} dependency cache is disabled: {e:?}",
+                make_warning_label()
+            );
+            None
+        })
+    };
+    let mut sessions = Vec::new();
+
+    // 結果が並列数や完了順に左右されないよう、幅優先探索を深さごとに区切り、各深さは名前順に取り
+    // 込む
+    let mut visited = HashSet::new();
+    let mut resolved = HashMap::new();
+    let mut failures = Vec::new();
+    let mut cache_hits = 0;
+    let mut frontier = config
+        .scoop_apps
+        .iter()
+        .map(|spec| spec.app.clone())
+        .collect_vec();
+
+    while !frontier.is_empty() {
+        let level = frontier
+            .into_iter()
+            .filter(|app| visited.insert(app.clone()))
+            .sorted_by_key(|app| app.to_string())
+            .collect_vec();
+
+        // キャッシュのキーは既定のアーキテクチャなので、個別に指定されたものはキャッシュしない
+        let cached = level
+            .iter()
+            .map(|app| {
+                cache
+                    .as_ref()
+                    .filter(|_| arch_of(app) == arch)
+                    .and_then(|cache| cache.get(app))
+                    .cloned()
+            })
+            .collect_vec();
+        let misses = level
+            .iter()
+            .zip(&cached)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(app, _)| app.clone())
+            .collect_vec();
+        cache_hits += level.len() - misses.len();
+        let mut results = match &mut native {
+            Some(native) => misses
+                .iter()
+                .map(|app| {
+                    eprintln!("{} {}", make_sublabel("Resolving"), app);
+                    native.dependencies_of(app, arch_of(app))
+                })
+                .collect_vec(),
+            None => resolve_with_sessions(client, &mut sessions, resolve.jobs(), &misses)?,
+        }
+        .into_iter();
+
+        frontier = Vec::new();
+        for (app, cached) in level.into_iter().zip(cached) {
+            let result = match cached {
+                Some(dependencies) => Ok(dependencies),
+                None => results.next().expect("a result for each uncached app"),
+            };
+            match result {
+                Ok(dependencies) => {
+                    if let Some(cache) = &mut cache
+                        && arch_of(&app) == arch
+                    {
+                        cache.insert(app.clone(), dependencies.clone());
+                    }
+                    frontier.extend(dependencies.iter().cloned());
+                    resolved.insert(app, dependencies);
+                }
+                Err(e) => failures.push(ResolutionFailure {
+                    optional: app_specs.get(&app).is_some_and(|spec| spec.optional),
+                    error: e.chain().join(": "),
+                    app,
+                }),
             }
-        };
+        }
+    }
 
-        to_resolve.extend(dependencies.clone());
-        resolved.insert(app.clone(), dependencies);
+    if cache_hits > 0 {
+        eprintln!(
+            "{} {cache_hits} app(s) from the dependency cache",
+            make_sublabel("Reused")
+        );
+    }
+    if let Some(cache) = &cache
+        && let Err(e) = cache.save()
+    {
+        eprintln!(
+            "{} failed to save dependency cache: {e:?}",
+            make_warning_label()
+        );
+    }
+
+    describe_resolution_failures(&failures, policy);
+    let fatal = failures.iter().filter(|f| !f.optional).count();
+    if policy == ResolutionFailurePolicy::Abort && fatal > 0 {
+        bail!("failed to resolve dependencies of {fatal} app(s)");
+    }
+
+    // scoop は global なアプリケーションの依存関係も global にインストールする
+    let user_apps = collect_reachable(
+        &resolved,
+        config
+            .scoop_apps
+            .iter()
+            .filter(|spec| !spec.global)
+            .map(|spec| &spec.app),
+    );
+    let global_apps = collect_reachable(
+        &resolved,
+        config
+            .scoop_apps
+            .iter()
+            .filter(|spec| spec.global)
+            .map(|spec| &spec.app),
+    );
+
+    // 既知のバケットは宣言しなくても使えるので、使われているものを必要なバケットに加える
+    let mut scoop_buckets = config.scoop_buckets.clone();
+    let used_buckets = config
+        .scoop_apps
+        .iter()
+        .map(|spec| &spec.app)
+        .chain(resolved.values().flatten())
+        .map(|app| &*app.bucket_name)
+        .unique()
+        .sorted()
+        .collect_vec();
+    for name in used_buckets {
+        if !scoop_buckets.iter().any(|bucket| bucket.name == name)
+            && let Some(bucket) = ScoopBucket::known(name)
+        {
+            scoop_buckets.push(bucket);
+        }
     }
 
     Ok(RequiredThings {
-        scoop_buckets: config.scoop_buckets.clone(),
+        scoop_buckets,
         scoop_apps: resolved,
+        app_specs,
+        default_arch: config.default_arch,
+        user_apps,
+        global_apps,
+        unresolved: failures,
+        keep_unresolved: policy != ResolutionFailurePolicy::Warn,
     })
 }
 
+/// `scoop depends` は一回ごとに時間がかかるので、最大 `jobs` 個のセッションで手分けして解決しま
+/// す。足りないセッションは `client` から開き、`sessions` に残して次の呼び出しでも使う。
+fn resolve_with_sessions(
+    client: &mut dyn Backend,
+    sessions: &mut Vec<Box<dyn Backend>>,
+    jobs: NonZeroUsize,
+    apps: &[ScoopApp],
+) -> Result<Vec<Result<HashSet<ScoopApp>>>> {
+    let workers = jobs.get().min(apps.len());
+    while sessions.len() + 1 < workers {
+        let session = client
+            .open_session()
+            .wrap_err("failed to open a session for dependency resolution")?;
+        sessions.push(session);
+    }
+
+    let backends = iter::once(&mut *client as &mut dyn Backend)
+        .chain(
+            sessions
+                .iter_mut()
+                .map(|session| &mut **session as &mut dyn Backend),
+        )
+        .take(workers)
+        .collect_vec();
+
+    Ok(resolve_in_parallel(backends, apps))
+}
+
+/// 各アプリケーションの依存関係を、与えられたバックエンドで手分けして取得します。結果は `apps`
+/// と同じ順に返します。
+fn resolve_in_parallel(
+    backends: Vec<&mut dyn Backend>,
+    apps: &[ScoopApp],
+) -> Vec<Result<HashSet<ScoopApp>>> {
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let handles = backends
+            .into_iter()
+            .map(|backend| {
+                let next = &next;
+                scope.spawn(move || {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(app) = apps.get(index) else {
+                            break;
+                        };
+                        eprintln!("{} {}", make_sublabel("Resolving"), app);
+                        results.push((index, backend.dependencies_of(app)));
+                    }
+                    results
+                })
+            })
+            .collect_vec();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .expect("dependency resolution thread panicked")
+            })
+            .collect_vec()
+    });
+
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// 依存関係を解決できなかったアプリケーション。
 #[derive(Debug, Clone, PartialEq, Eq)]
+struct ResolutionFailure {
+    app: ScoopApp,
+    /// 設定ファイルで `optional` とされているかどうか。
+    optional: bool,
+    error: String,
+}
+
+/// 依存関係を解決できなかったアプリケーションをまとめて表示します。
+fn describe_resolution_failures(failures: &[ResolutionFailure], policy: ResolutionFailurePolicy) {
+    if failures.is_empty() {
+        return;
+    }
+
+    eprintln!();
+    eprintln!(
+        "{} {} app(s) could not be resolved",
+        make_sublabel("Summary"),
+        failures.len()
+    );
+    for failure in failures.iter().sorted_by_key(|f| f.app.to_string()) {
+        let consequence = match policy {
+            _ if failure.optional => "optional, skipped",
+            ResolutionFailurePolicy::Abort => "aborting",
+            ResolutionFailurePolicy::Keep => {
+                "not installed; if installed, kept along with all unmanaged apps"
+            }
+            ResolutionFailurePolicy::Warn => "treated as not required",
+        };
+        eprintln!(
+            "{:>8} {} ({}): {}",
+            "app".red(),
+            failure.app,
+            consequence.yellow(),
+            failure.error
+        );
+    }
+    eprintln!();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct InstalledThings {
     scoop_buckets: Vec<ScoopBucket>,
-    scoop_apps: HashSet<ScoopApp>,
+    /// ユーザー単位でインストールされているアプリケーション。
+    scoop_apps: HashMap<ScoopApp, InstalledApp>,
+    /// 全ユーザー向けにインストールされているアプリケーション。同じアプリケーションがユーザー単位
+    /// にもインストールされていることがある。
+    global_scoop_apps: HashMap<ScoopApp, InstalledApp>,
 }
 
-// インストールされているアプリケーションのリストを取得
-fn get_installed_things(client: &mut ScoopClient) -> Result<InstalledThings> {
-    println!("{} currently installed applications", make_label("Loading"));
-    let exported = client
-        .exec(&["export"])
-        .wrap_err("failed to invoke `scoop export`")?;
-    if !exported.status.success() {
-        bail!("failed to export scoop status: {}", exported.stderr);
+impl InstalledThings {
+    /// 指定のスコープにインストールされているアプリケーションを返します。
+    fn get(&self, app: &ScoopApp, global: bool) -> Option<&InstalledApp> {
+        if global {
+            self.global_scoop_apps.get(app)
+        } else {
+            self.scoop_apps.get(app)
+        }
     }
 
-    #[derive(Deserialize)]
-    struct ExportedScoopData {
-        buckets: Vec<ExportedScoopBucket>,
-        apps: Vec<ExportedScoopApp>,
+    /// いずれかのスコープにインストールされているかどうかを返します。
+    fn contains(&self, app: &ScoopApp) -> bool {
+        self.scoop_apps.contains_key(app) || self.global_scoop_apps.contains_key(app)
     }
 
-    #[derive(Deserialize)]
-    struct ExportedScoopBucket {
-        #[serde(rename = "Name")]
-        name: String,
-        #[serde(rename = "Source")]
-        source: String,
+    /// インストールされているアプリケーションを、global かどうかと合わせてすべて返します。
+    fn apps(&self) -> impl Iterator<Item = (&ScoopApp, bool, &InstalledApp)> {
+        let user = self
+            .scoop_apps
+            .iter()
+            .map(|(app, installed)| (app, false, installed));
+        let global = self
+            .global_scoop_apps
+            .iter()
+            .map(|(app, installed)| (app, true, installed));
+
+        user.chain(global)
     }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct InstalledApp {
+    version: String,
+    arch: Arch,
+    /// 現在のバージョンで固定 (hold) されているかどうか。
+    held: bool,
+}
+
+// インストールされているアプリケーションのリストを取得
+fn get_installed_things(client: &mut dyn Backend) -> Result<InstalledThings> {
+    eprintln!("{} currently installed applications", make_label("Loading"));
+    client.list_installed()
+}
 
-    #[derive(Deserialize)]
-    struct ExportedScoopApp {
-        #[serde(rename = "Name")]
-        name: String,
-        #[serde(rename = "Source")]
-        bucket: Option<String>,
+/// 古くなっているアプリケーションを取得します。更新が有効になっていないアプリケーションや、バー
+/// ジョンが固定されているアプリケーションは含まない。
+fn get_outdated_apps(
+    client: &mut dyn Backend,
+    config: &Config,
+    options: &PlanOptions,
+    required_things: &RequiredThings,
+    installed_things: &InstalledThings,
+) -> Result<HashMap<ScoopApp, String>> {
+    let candidates = installed_things
+        .apps()
+        .filter(|(app, _, _)| required_things.scoop_apps.contains_key(app))
+        .filter(|(app, global, _)| required_things.spec_of(app, *global).version.is_none())
+        .filter(|(app, _, _)| config.update.should_update(app, options.update))
+        .map(|(app, _, _)| app)
+        .unique()
+        .collect_vec();
+    if candidates.is_empty() {
+        return Ok(HashMap::new());
     }
 
-    let data: ExportedScoopData = serde_json::from_str(&exported.stdout)
-        .into_diagnostic()
-        .wrap_err("failed to parse `scoop export` output")?;
+    eprintln!("{} outdated applications", make_label("Checking"));
+    let outdated = client.outdated_apps()?;
 
-    Ok(InstalledThings {
-        scoop_buckets: data
-            .buckets
-            .iter()
-            .map(|bucket| ScoopBucket {
-                name: bucket.name.clone(),
-                source: bucket.source.clone(),
-            })
-            .collect(),
-        scoop_apps: data
-            .apps
-            .iter()
-            .filter_map(|app| {
-                app.bucket.as_ref().map(|bucket| ScoopApp {
-                    name: app.name.clone(),
-                    bucket_name: bucket.clone(),
-                })
-            })
-            .collect(),
-    })
+    Ok(candidates
+        .into_iter()
+        .filter_map(|app| {
+            outdated
+                .iter()
+                .find(|outdated| outdated.app == *app)
+                .map(|outdated| (app.clone(), outdated.latest_version.clone()))
+        })
+        .collect())
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct ThingsToUninstall {
     scoop_buckets: HashSet<ScoopBucket>,
-    scoop_apps: HashSet<ScoopApp>,
+    /// アンインストールするアプリケーション。`global` 以外の属性は使わない。
+    scoop_apps: HashSet<AppSpec>,
+    /// 設定ファイルに記載されていないが、無視するパターンに一致するなどして残すバケット。
+    kept_scoop_buckets: HashSet<ScoopBucket>,
+    /// 設定ファイルに記載されていないが、無視するパターンに一致するなどして残すアプリケーション。
+    kept_scoop_apps: HashSet<AppSpec>,
 }
 
 impl ThingsToUninstall {
@@ -284,6 +1185,34 @@ impl ThingsToUninstall {
         self.scoop_apps.is_empty() && self.scoop_buckets.is_empty()
     }
 
+    fn is_nothing_kept(&self) -> bool {
+        self.kept_scoop_apps.is_empty() && self.kept_scoop_buckets.is_empty()
+    }
+
+    /// アンインストールする予定だった項目をすべて残すようにします。
+    fn keep_all(&mut self) {
+        self.kept_scoop_buckets.extend(self.scoop_buckets.drain());
+        self.kept_scoop_apps.extend(self.scoop_apps.drain());
+    }
+
+    /// 無視するパターンに一致するなどして残す項目を表示します。
+    fn describe_kept(&self) {
+        if self.is_nothing_kept() {
+            return;
+        }
+
+        println!();
+        println!("Following items are {}", "unmanaged, kept".dimmed().bold());
+
+        for bucket in &self.kept_scoop_buckets {
+            println!("{}", format_item_keep("bucket", &bucket.name));
+        }
+
+        for app in &self.kept_scoop_apps {
+            println!("{}", format_item_keep("app", app.describe()));
+        }
+    }
+
     fn describe_plan(&self) {
         if self.is_empty() {
             return;
@@ -297,7 +1226,7 @@ impl ThingsToUninstall {
         }
 
         for app in &self.scoop_apps {
-            println!("{}", format_item_remove("app", app));
+            println!("{}", format_item_remove("app", app.describe()));
         }
     }
 }
@@ -305,159 +1234,1006 @@ impl ThingsToUninstall {
 fn compute_things_to_uninstall(
     installed_things: &InstalledThings,
     required_things: &RequiredThings,
+    ignore: &IgnoreRules,
 ) -> ThingsToUninstall {
     let mut scoop_buckets = HashSet::new();
     let mut scoop_apps = HashSet::new();
+    let mut kept_scoop_buckets = HashSet::new();
+    let mut kept_scoop_apps = HashSet::new();
 
     for bucket in &installed_things.scoop_buckets {
-        if !required_things.scoop_buckets.contains(bucket) {
+        if required_things.scoop_buckets.contains(bucket) {
+            continue;
+        }
+
+        if ignore.ignores_bucket(&bucket.name) {
+            kept_scoop_buckets.insert(bucket.clone());
+        } else {
             scoop_buckets.insert(bucket.clone());
         }
     }
 
-    for app in &installed_things.scoop_apps {
-        if !required_things.scoop_apps.contains_key(app) {
-            scoop_apps.insert(app.clone());
+    // 解決できなかったアプリケーションがインストール済みなら、その依存関係がわからないので、どのア
+    // プリケーションもその依存関係かもしれない。必要ないと言い切れないものはアンインストールしない
+    let keeps_all_apps = required_things.keep_unresolved
+        && required_things
+            .unresolved
+            .iter()
+            .any(|failure| installed_things.contains(&failure.app));
+
+    for (app, global, _) in installed_things.apps() {
+        // ユーザー単位で必要なアプリケーションは global にインストールされていても動くので、ユーザ
+        // ー単位のものがなければ global なものを残す
+        let required = required_things.requires(app, global)
+            || required_things.keeps_unresolved(app)
+            || (global
+                && required_things.requires(app, false)
+                && installed_things.get(app, false).is_none());
+        if required {
+            continue;
+        }
+
+        let spec = AppSpec {
+            global,
+            ..AppSpec::unpinned(app.clone())
+        };
+        if ignore.ignores_app(app) || keeps_all_apps {
+            kept_scoop_apps.insert(spec);
+        } else {
+            scoop_apps.insert(spec);
         }
     }
 
     ThingsToUninstall {
         scoop_buckets,
         scoop_apps,
+        kept_scoop_buckets,
+        kept_scoop_apps,
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct ThingsToInstall {
     scoop_buckets: HashSet<ScoopBucket>,
-    scoop_apps: HashSet<ScoopApp>,
+    scoop_apps: HashSet<AppSpec>,
+    /// 固定されたバージョンやアーキテクチャがインストール済みのものと異なり、入れ直すアプリケー
+    /// ション。
+    scoop_apps_to_reinstall: HashSet<Reinstall>,
+    /// 古くなっていて、最新のバージョンに更新するアプリケーション。
+    scoop_apps_to_upgrade: HashSet<Upgrade>,
+    /// 固定するアプリケーション。`global` 以外の属性は使わない。
+    scoop_apps_to_hold: HashSet<AppSpec>,
+    /// 固定を解除するアプリケーション。`global` 以外の属性は使わない。
+    scoop_apps_to_unhold: HashSet<AppSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Upgrade {
+    app: ScoopApp,
+    global: bool,
+    installed_version: String,
+    latest_version: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Reinstall {
+    spec: AppSpec,
+    installed_version: String,
+    installed_arch: Arch,
+}
+
+impl Reinstall {
+    /// 入れ直しによって変わる項目を、変更前と変更後の表示用文字列として返します。
+    fn changes(&self) -> (String, String) {
+        let mut from = Vec::new();
+        let mut to = Vec::new();
+        if let Some(version) = &self.spec.version
+            && *version != self.installed_version
+        {
+            from.push(self.installed_version.clone());
+            to.push(version.clone());
+        }
+        if let Some(arch) = self.spec.arch
+            && arch != self.installed_arch
+        {
+            from.push(self.installed_arch.to_string());
+            to.push(arch.to_string());
+        }
+
+        (from.join(", "), to.join(", "))
+    }
+
+    /// 入れ直す前にインストールされていたものを返します。入れ直しに失敗したときに元に戻すため。
+    fn previous(&self) -> AppSpec {
+        AppSpec {
+            version: Some(self.installed_version.clone()),
+            arch: Some(self.installed_arch),
+            global: self.spec.global,
+            ..AppSpec::unpinned(self.spec.app.clone())
+        }
+    }
 }
 
 impl ThingsToInstall {
     fn is_empty(&self) -> bool {
-        self.scoop_apps.is_empty() && self.scoop_buckets.is_empty()
+        self.scoop_apps.is_empty()
+            && self.scoop_buckets.is_empty()
+            && self.scoop_apps_to_reinstall.is_empty()
+            && self.scoop_apps_to_upgrade.is_empty()
+            && self.scoop_apps_to_hold.is_empty()
+            && self.scoop_apps_to_unhold.is_empty()
     }
 
     fn describe_plan(&self) {
-        if self.is_empty() {
-            return;
-        }
+        if !self.scoop_buckets.is_empty()
+            || !self.scoop_apps.is_empty()
+            || !self.scoop_apps_to_reinstall.is_empty()
+        {
+            println!();
+            println!("Following items will be {}", "installed".green().bold());
+
+            for bucket in &self.scoop_buckets {
+                println!("{}", format_item_add("bucket", &bucket.name));
+            }
 
-        println!();
-        println!("Following items will be {}", "installed".green().bold());
+            for app in &self.scoop_apps {
+                println!("{}", format_item_add("app", app.describe()));
+            }
 
-        for bucket in &self.scoop_buckets {
-            println!("{}", format_item_add("bucket", &bucket.name));
+            for reinstall in &self.scoop_apps_to_reinstall {
+                let (from, to) = reinstall.changes();
+                println!(
+                    "{}",
+                    format_item_change(
+                        "app",
+                        describe_scope(&reinstall.spec.app, reinstall.spec.global),
+                        &from,
+                        &to
+                    )
+                );
+            }
         }
 
-        for app in &self.scoop_apps {
-            println!("{}", format_item_add("app", app));
+        if !self.scoop_apps_to_upgrade.is_empty() {
+            println!();
+            println!("Following items will be {}", "upgraded".blue().bold());
+
+            for upgrade in &self.scoop_apps_to_upgrade {
+                println!(
+                    "{}",
+                    format_item_upgrade(
+                        "app",
+                        describe_scope(&upgrade.app, upgrade.global),
+                        &upgrade.installed_version,
+                        &upgrade.latest_version
+                    )
+                );
+            }
+        }
+
+        if !self.scoop_apps_to_hold.is_empty() || !self.scoop_apps_to_unhold.is_empty() {
+            println!();
+            println!(
+                "Following items will be {}",
+                "held or unheld".yellow().bold()
+            );
+
+            for app in &self.scoop_apps_to_hold {
+                println!(
+                    "{}",
+                    format_item_change("app", app.describe(), "not held", "held")
+                );
+            }
+
+            for app in &self.scoop_apps_to_unhold {
+                println!(
+                    "{}",
+                    format_item_change("app", app.describe(), "held", "not held")
+                );
+            }
         }
     }
 }
 
 fn compute_things_to_install(
     installed_things: &InstalledThings,
-    required_things: RequiredThings,
+    required_things: &RequiredThings,
+    outdated_apps: &HashMap<ScoopApp, String>,
 ) -> ThingsToInstall {
     let mut scoop_buckets = HashSet::new();
     let mut scoop_apps = HashSet::new();
+    let mut scoop_apps_to_reinstall = HashSet::new();
+    let mut scoop_apps_to_upgrade = HashSet::new();
+    let mut scoop_apps_to_hold = HashSet::new();
+    let mut scoop_apps_to_unhold = HashSet::new();
 
-    for bucket in required_things.scoop_buckets {
-        if !installed_things.scoop_buckets.contains(&bucket) {
-            scoop_buckets.insert(bucket);
+    for bucket in &required_things.scoop_buckets {
+        if !installed_things.scoop_buckets.contains(bucket) {
+            scoop_buckets.insert(bucket.clone());
         }
     }
 
     for app in required_things.scoop_apps.keys() {
-        if !installed_things.scoop_apps.contains(app) {
-            scoop_apps.insert(app.clone());
+        // 各スコープについて、要件を満たすべきインストール済みのアプリケーションを決める。ユーザー単
+        // 位の要件は global にインストールされたものでも満たせるが、逆は満たせない。
+        let mut targets = Vec::new();
+        let requires_global = required_things.requires(app, true);
+        if requires_global {
+            targets.push((true, installed_things.get(app, true)));
+        }
+        if required_things.requires(app, false) {
+            match (
+                installed_things.get(app, false),
+                installed_things.get(app, true),
+            ) {
+                (Some(installed), _) => targets.push((false, Some(installed))),
+                (None, Some(_)) if requires_global => {}
+                (None, Some(installed)) => targets.push((true, Some(installed))),
+                (None, None) if requires_global => {}
+                (None, None) => targets.push((false, None)),
+            }
+        }
+
+        for (global, installed) in targets {
+            let spec = required_things.spec_of(app, global);
+            let Some(installed) = installed else {
+                scoop_apps.insert(spec);
+                continue;
+            };
+
+            let version_differs = spec
+                .version
+                .as_ref()
+                .is_some_and(|version| *version != installed.version);
+            let arch_differs = spec.arch.is_some_and(|arch| arch != installed.arch);
+            if version_differs || arch_differs {
+                // 入れ直したアプリケーションはインストール時に固定されるので、固定状態の変更は不要
+                scoop_apps_to_reinstall.insert(Reinstall {
+                    spec,
+                    installed_version: installed.version.clone(),
+                    installed_arch: installed.arch,
+                });
+                continue;
+            }
+
+            let scoped = AppSpec {
+                global,
+                ..AppSpec::unpinned(app.clone())
+            };
+            if spec.hold && !installed.held {
+                scoop_apps_to_hold.insert(scoped);
+            } else if !spec.hold && installed.held {
+                scoop_apps_to_unhold.insert(scoped);
+            }
+
+            // 入れ直すアプリケーションはその時点で最新のバージョンになるので、更新は入れ直さないも
+            // のだけ。固定するアプリケーションは意図的に止めているバージョンを越えないよう更新しない。
+            if let Some(latest_version) = outdated_apps.get(app)
+                && !spec.hold
+            {
+                scoop_apps_to_upgrade.insert(Upgrade {
+                    app: app.clone(),
+                    global,
+                    installed_version: installed.version.clone(),
+                    latest_version: latest_version.clone(),
+                });
+            }
         }
     }
 
     ThingsToInstall {
         scoop_buckets,
         scoop_apps,
+        scoop_apps_to_reinstall,
+        scoop_apps_to_upgrade,
+        scoop_apps_to_hold,
+        scoop_apps_to_unhold,
     }
 }
 
 fn uninstall_buckets<'a>(
-    client: &mut ScoopClient,
+    client: &mut dyn Backend,
     buckets: impl IntoIterator<Item = &'a ScoopBucket>,
 ) -> Result<()> {
-    let mut args = vec!["bucket", "rm"];
-    let bucket_names = buckets.into_iter().map(|b| &*b.name).collect_vec();
-    if bucket_names.is_empty() {
+    let buckets = buckets.into_iter().cloned().collect_vec();
+    if buckets.is_empty() {
         return Ok(()); // Nothing to uninstall
     }
-    args.extend(&bucket_names);
-    let output = client.exec(&args).wrap_err("failed to uninstall buckets")?;
-    if !output.status.success() {
-        bail!("failed to uninstall buckets: {}", output.stderr.trim());
-    }
 
-    Ok(())
+    client.remove_buckets(&buckets)
 }
 
 fn uninstall_apps<'a>(
-    client: &mut ScoopClient,
-    apps: impl IntoIterator<Item = &'a ScoopApp>,
+    client: &mut dyn Backend,
+    apps: impl IntoIterator<Item = &'a AppSpec>,
 ) -> Result<()> {
-    let mut args = vec!["uninstall"];
-    let app_ids = apps.into_iter().map(|app| app.to_string()).collect_vec();
-    args.extend(app_ids.iter().map(|id| id.as_str()));
-    if app_ids.is_empty() {
+    let apps = apps.into_iter().cloned().collect_vec();
+    if apps.is_empty() {
         return Ok(()); // Nothing to uninstall
     }
-    let output = client
-        .exec(&args)
-        .wrap_err("failed to uninstall applications")?;
-    if !output.status.success() {
-        bail!("failed to uninstall applications: {}", output.stderr.trim());
-    }
 
-    Ok(())
+    client.uninstall_apps(&apps)
 }
 
 fn install_buckets<'a>(
-    client: &mut ScoopClient,
+    client: &mut dyn Backend,
     buckets: impl IntoIterator<Item = &'a ScoopBucket>,
 ) -> Result<()> {
     for bucket in buckets {
-        let output = client
-            .exec(&["bucket", "add", &bucket.name, &bucket.source])
-            .wrap_err_with(|| {
-                miette!(
-                    "failed to install bucket {} from {}",
-                    bucket.name,
-                    bucket.source
-                )
-            })?;
-        if !output.status.success() {
-            bail!(
-                "failed to install bucket {}: {}",
-                bucket.name,
-                output.stderr.trim()
-            );
-        }
+        client.add_bucket(bucket)?;
     }
 
     Ok(())
 }
 
 fn install_apps<'a>(
-    client: &mut ScoopClient,
-    apps: impl IntoIterator<Item = &'a ScoopApp>,
+    client: &mut dyn Backend,
+    apps: impl IntoIterator<Item = &'a AppSpec>,
 ) -> Result<()> {
-    let mut args = vec!["install"];
-    let app_ids = apps.into_iter().map(|app| app.to_string()).collect_vec();
-    args.extend(app_ids.iter().map(|id| id.as_str()));
-    let output = client
-        .exec(&args)
-        .wrap_err("failed to install applications")?;
-    if !output.status.success() {
-        bail!("failed to install applications: {}", output.stderr.trim());
+    let apps = apps.into_iter().cloned().collect_vec();
+    if apps.is_empty() {
+        return Ok(()); // Nothing to install
+    }
+
+    client.install_apps(&apps)?;
+
+    let to_hold = apps.into_iter().filter(|spec| spec.hold).collect_vec();
+    if !to_hold.is_empty() {
+        client.hold_apps(&to_hold)?;
     }
 
     Ok(())
 }
+
+fn hold_apps<'a>(
+    client: &mut dyn Backend,
+    apps: impl IntoIterator<Item = &'a AppSpec>,
+) -> Result<()> {
+    let apps = apps.into_iter().cloned().collect_vec();
+    if apps.is_empty() {
+        return Ok(()); // Nothing to hold
+    }
+
+    client.hold_apps(&apps)
+}
+
+fn unhold_apps<'a>(
+    client: &mut dyn Backend,
+    apps: impl IntoIterator<Item = &'a AppSpec>,
+) -> Result<()> {
+    let apps = apps.into_iter().cloned().collect_vec();
+    if apps.is_empty() {
+        return Ok(()); // Nothing to unhold
+    }
+
+    client.unhold_apps(&apps)
+}
+
+/// インストール済みのアプリケーションを一度アンインストールし、指定のバージョンで入れ直します。
+///
+/// scoop は同じアプリケーションの別のバージョンを並べてインストールできないので、先にアンインスト
+/// ールする必要がある。インストールに失敗した場合は、アプリケーションがなくなったままにならない
+/// よう元のバージョンを入れ直してからエラーを返す。
+fn reinstall_apps<'a>(
+    client: &mut dyn Backend,
+    reinstalls: impl IntoIterator<Item = &'a Reinstall>,
+) -> Result<()> {
+    for reinstall in reinstalls {
+        let app = &reinstall.spec.app;
+        uninstall_apps(client, [&reinstall.spec])?;
+        let Err(e) = install_apps(client, [&reinstall.spec]) else {
+            continue;
+        };
+
+        let previous = reinstall.previous();
+        return match client.install_apps(&[previous]) {
+            Ok(()) => Err(e.wrap_err(format!(
+                "failed to reinstall {app}; restored the previous version {}",
+                reinstall.installed_version
+            ))),
+            Err(restore_error) => Err(e.wrap_err(format!(
+                "failed to reinstall {app}, and failed to restore the previous version {}: \
+                 {restore_error}",
+                reinstall.installed_version
+            ))),
+        };
+    }
+
+    Ok(())
+}
+
+/// 古くなったアプリケーションを最新のバージョンに更新します。
+fn upgrade_apps<'a>(
+    client: &mut dyn Backend,
+    upgrades: impl IntoIterator<Item = &'a Upgrade>,
+) -> Result<()> {
+    let apps = upgrades
+        .into_iter()
+        .map(|upgrade| AppSpec {
+            global: upgrade.global,
+            ..AppSpec::unpinned(upgrade.app.clone())
+        })
+        .collect_vec();
+    if apps.is_empty() {
+        return Ok(()); // Nothing to upgrade
+    }
+
+    client.update_apps(&apps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{FakeState, Operation};
+    use crate::test_util::{TempDir, app, write};
+
+    fn fake_backend(state: &str) -> FakeBackend {
+        let state: FakeState = serde_yaml::from_str(state).unwrap();
+        FakeBackend::new(state)
+    }
+
+    fn resolve_options() -> ResolveOptions {
+        ResolveOptions {
+            jobs: Some(NonZeroUsize::MIN),
+            resolver: Resolver::Scoop,
+            no_cache: true,
+        }
+    }
+
+    fn bucket(name: &str) -> ScoopBucket {
+        ScoopBucket {
+            name: name.to_string(),
+            source: format!("https://example.com/{name}"),
+        }
+    }
+
+    fn plan(state: &str, config: &str, options: &PlanOptions) -> Plan {
+        let config: Config = serde_yaml::from_str(config).unwrap();
+        compute_plan(
+            &mut fake_backend(state),
+            &config,
+            options,
+            &resolve_options(),
+        )
+        .unwrap()
+    }
+
+    fn describe_apps<'a>(specs: impl IntoIterator<Item = &'a AppSpec>) -> Vec<String> {
+        specs.into_iter().map(AppSpec::describe).sorted().collect()
+    }
+
+    fn bucket_names(buckets: &HashSet<ScoopBucket>) -> Vec<&str> {
+        buckets
+            .iter()
+            .map(|bucket| &*bucket.name)
+            .sorted()
+            .collect()
+    }
+
+    const STATE: &str = "
+buckets:
+  main:
+    source: https://example.com/main
+    apps:
+      7zip: {}
+      git: {}
+  extras:
+    source: https://example.com/extras
+    apps:
+      foo: { depends: [main/7zip] }
+  old:
+    source: https://example.com/old
+installed_buckets: [main, extras, old]
+installed_apps: [main/7zip, main/git]
+";
+
+    const CONFIG: &str = "
+scoop_buckets:
+  - { name: main, source: https://example.com/main }
+  - { name: extras, source: https://example.com/extras }
+scoop_apps: [main/7zip, extras/foo]
+";
+
+    #[test]
+    fn executes_plan_in_order() {
+        let mut fake = fake_backend(STATE);
+        let config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        let plan = compute_plan(
+            &mut fake,
+            &config,
+            &PlanOptions::default(),
+            &resolve_options(),
+        )
+        .unwrap();
+
+        // 計画を立てるだけでは何も変更しない
+        let planned = fake.operations().len();
+        assert!(!fake.operations().iter().any(|op| matches!(
+            op,
+            Operation::Install(_) | Operation::Uninstall(_) | Operation::RemoveBuckets(_)
+        )));
+
+        execute_plan(&mut fake, &plan.to_uninstall, &plan.to_install).unwrap();
+        assert_eq!(
+            fake.operations()[planned..],
+            [
+                Operation::Uninstall(vec![AppSpec::unpinned(app("main/git"))]),
+                Operation::RemoveBuckets(vec![bucket("old")]),
+                Operation::Install(vec![AppSpec::unpinned(app("extras/foo"))]),
+            ]
+        );
+    }
+
+    #[test]
+    fn dry_run_exits_with_pending_changes_code() {
+        let dir = TempDir::new();
+        let path = dir.path().join("main.yaml");
+        write(&path, CONFIG);
+        let context = ConfigContext::current(Vec::new());
+        let apply_dry_run = |fake: &mut FakeBackend, path: &Path| {
+            let options = PlanOptions::default();
+            apply(
+                fake,
+                path,
+                &context,
+                false,
+                true,
+                &options,
+                &resolve_options(),
+            )
+            .unwrap()
+        };
+
+        let mut fake = fake_backend(STATE);
+        assert_eq!(
+            apply_dry_run(&mut fake, &path),
+            ExitCode::from(EXIT_CHANGES_PENDING)
+        );
+        assert!(!fake.operations().iter().any(|op| matches!(
+            op,
+            Operation::Install(_) | Operation::Uninstall(_) | Operation::RemoveBuckets(_)
+        )));
+
+        // 変更がなければ成功として終了する
+        let satisfied = dir.path().join("satisfied.yaml");
+        write(
+            &satisfied,
+            "
+scoop_buckets:
+  - { name: main, source: https://example.com/main }
+  - { name: extras, source: https://example.com/extras }
+  - { name: old, source: https://example.com/old }
+scoop_apps: [main/7zip, main/git]
+",
+        );
+        let mut fake = fake_backend(STATE);
+        assert_eq!(apply_dry_run(&mut fake, &satisfied), ExitCode::SUCCESS);
+    }
+
+    #[test]
+    fn reports_injected_install_failure() {
+        let state = format!("{STATE}failures: [{{ operation: install, target: extras/foo }}]\n");
+        let mut fake = fake_backend(&state);
+        let config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        let plan = compute_plan(
+            &mut fake,
+            &config,
+            &PlanOptions::default(),
+            &resolve_options(),
+        )
+        .unwrap();
+
+        let planned = fake.operations().len();
+        let error = execute_plan(&mut fake, &plan.to_uninstall, &plan.to_install).unwrap_err();
+        let messages = error.chain().map(ToString::to_string).collect_vec();
+        assert_eq!(messages[0], "failed to install applications");
+        assert!(messages[1].contains("injected failure"), "{messages:?}");
+
+        // 失敗したインストールより後の操作は行わない
+        assert_eq!(
+            fake.operations()[planned..].last(),
+            Some(&Operation::Install(vec![AppSpec::unpinned(app(
+                "extras/foo"
+            ))]))
+        );
+    }
+
+    #[test]
+    fn restores_previous_version_when_reinstall_fails() {
+        let mut fake = fake_backend(
+            "
+buckets:
+  main:
+    source: https://example.com/main
+    apps:
+      git: { version: 2.44.0 }
+installed_buckets: [main]
+installed_apps: [main/git@2.43.0]
+failures: [{ operation: install, target: main/git@2.45.0 }]
+",
+        );
+        let config: Config = serde_yaml::from_str(
+            "
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps: [main/git@2.45.0]
+",
+        )
+        .unwrap();
+        let plan = compute_plan(
+            &mut fake,
+            &config,
+            &PlanOptions::default(),
+            &resolve_options(),
+        )
+        .unwrap();
+
+        let planned = fake.operations().len();
+        let error = execute_plan(&mut fake, &plan.to_uninstall, &plan.to_install).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "failed to reinstall main/git; restored the previous version 2.43.0"
+        );
+
+        let pinned: AppSpec = "main/git@2.45.0".parse().unwrap();
+        let previous = AppSpec {
+            version: Some("2.43.0".to_string()),
+            arch: Some(Arch::host()),
+            ..AppSpec::unpinned(app("main/git"))
+        };
+        assert_eq!(
+            fake.operations()[planned..],
+            [
+                Operation::Uninstall(vec![pinned.clone()]),
+                Operation::Install(vec![pinned]),
+                Operation::Install(vec![previous]),
+            ]
+        );
+        let installed = fake.list_installed().unwrap();
+        assert_eq!(installed.scoop_apps[&app("main/git")].version, "2.43.0");
+    }
+
+    #[test]
+    fn matches_outdated_apps_by_bucket() {
+        let mut fake = fake_backend(
+            "
+buckets:
+  main:
+    source: https://example.com/main
+    apps:
+      foo: { version: 1.0.0 }
+  extras:
+    source: https://example.com/extras
+    apps:
+      foo: { version: 2.0.0 }
+installed_buckets: [main, extras]
+installed_apps: [main/foo@1.0.0, extras/foo@1.0.0]
+",
+        );
+        let config: Config = serde_yaml::from_str(
+            "
+scoop_buckets:
+  - { name: main, source: https://example.com/main }
+  - { name: extras, source: https://example.com/extras }
+scoop_apps: [main/foo]
+",
+        )
+        .unwrap();
+        let options = PlanOptions {
+            update: true,
+            ..PlanOptions::default()
+        };
+        let required = get_required_things(
+            &mut fake,
+            &config,
+            ResolutionFailurePolicy::Abort,
+            &resolve_options(),
+        )
+        .unwrap();
+        let installed = get_installed_things(&mut fake).unwrap();
+
+        // 古くなっているのは extras/foo だけなので、同じ名前の main/foo は更新しない
+        let outdated = get_outdated_apps(&mut fake, &config, &options, &required, &installed);
+        assert_eq!(outdated.unwrap(), HashMap::new());
+    }
+
+    #[test]
+    fn keeps_possible_dependencies_of_unresolved_apps() {
+        let state = "
+buckets:
+  main:
+    source: https://example.com/main
+    apps:
+      foo: { depends: [main/bar] }
+      bar: {}
+      baz: {}
+installed_buckets: [main]
+installed_apps: [main/foo, main/bar, main/baz]
+failures: [{ operation: depends, target: main/foo }]
+";
+        let config: Config = serde_yaml::from_str(
+            "
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps: [main/foo]
+",
+        )
+        .unwrap();
+        let plan_with = |policy| {
+            let options = PlanOptions {
+                on_resolution_failure: Some(policy),
+                ..PlanOptions::default()
+            };
+            compute_plan(
+                &mut fake_backend(state),
+                &config,
+                &options,
+                &resolve_options(),
+            )
+            .unwrap()
+        };
+        // main/foo の依存関係がわからないので、main/bar も main/baz も残す
+        let plan = plan_with(ResolutionFailurePolicy::Keep);
+        assert!(plan.to_uninstall.scoop_apps.is_empty());
+        assert_eq!(
+            describe_apps(&plan.to_uninstall.kept_scoop_apps),
+            ["main/bar", "main/baz"]
+        );
+
+        let plan = plan_with(ResolutionFailurePolicy::Warn);
+        assert_eq!(
+            describe_apps(&plan.to_uninstall.scoop_apps),
+            ["main/bar", "main/baz", "main/foo"]
+        );
+    }
+
+    #[test]
+    fn keeps_ignored_apps_and_buckets() {
+        let state = "
+buckets:
+  main:
+    source: https://example.com/main
+    apps:
+      7zip: {}
+      git: {}
+      gh: {}
+      less: {}
+  old:
+    source: https://example.com/old
+  private-tools:
+    source: https://example.com/private-tools
+installed_buckets: [main, old, private-tools]
+installed_apps: [main/7zip, main/git, main/gh, main/less]
+";
+        let plan = plan(
+            state,
+            "
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps: [main/7zip]
+ignore:
+  scoop_apps: [main/g*]
+  scoop_buckets: [private-*]
+",
+            &PlanOptions::default(),
+        );
+
+        let to_uninstall = &plan.to_uninstall;
+        assert_eq!(describe_apps(&to_uninstall.scoop_apps), ["main/less"]);
+        assert_eq!(
+            describe_apps(&to_uninstall.kept_scoop_apps),
+            ["main/gh", "main/git"]
+        );
+        assert_eq!(bucket_names(&to_uninstall.scoop_buckets), ["old"]);
+        assert_eq!(
+            bucket_names(&to_uninstall.kept_scoop_buckets),
+            ["private-tools"]
+        );
+    }
+
+    #[test]
+    fn additive_mode_never_uninstalls() {
+        let additive = PlanOptions {
+            additive: true,
+            ..PlanOptions::default()
+        };
+        let config_additive = format!("{CONFIG}additive: true\n");
+        for (config, options) in [
+            (CONFIG, &additive),
+            (&*config_additive, &PlanOptions::default()),
+        ] {
+            let plan = plan(STATE, config, options);
+            let to_uninstall = &plan.to_uninstall;
+            assert!(to_uninstall.is_empty());
+            assert_eq!(describe_apps(&to_uninstall.kept_scoop_apps), ["main/git"]);
+            assert_eq!(bucket_names(&to_uninstall.kept_scoop_buckets), ["old"]);
+            // インストールは通常どおり行う
+            assert_eq!(describe_apps(&plan.to_install.scoop_apps), ["extras/foo"]);
+        }
+    }
+
+    #[test]
+    fn global_installs_satisfy_user_requirements() {
+        let state = "
+buckets:
+  main:
+    source: https://example.com/main
+    apps:
+      7zip: {}
+      git: {}
+installed_buckets: [main]
+installed_apps:
+  - { name: git, bucket: main, global: true }
+  - main/7zip
+";
+        let plan = plan(
+            state,
+            "
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps:
+  - main/git
+  - { name: 7zip, bucket: main, global: true }
+",
+            &PlanOptions::default(),
+        );
+
+        // ユーザー単位の main/git は global なもので満たせるが、global な main/7zip はユーザー
+        // 単位のものでは満たせない
+        assert_eq!(
+            describe_apps(&plan.to_install.scoop_apps),
+            ["main/7zip [global]"]
+        );
+        assert_eq!(describe_apps(&plan.to_uninstall.scoop_apps), ["main/7zip"]);
+    }
+
+    #[test]
+    fn holds_and_unholds_apps_to_match_config() {
+        let state = "
+buckets:
+  main:
+    source: https://example.com/main
+    apps:
+      7zip: {}
+      git: {}
+      less: {}
+installed_buckets: [main]
+installed_apps:
+  - main/7zip
+  - { name: git, bucket: main, hold: true }
+  - { name: less, bucket: main, hold: true }
+";
+        let plan = plan(
+            state,
+            "
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps:
+  - { name: 7zip, bucket: main, hold: true }
+  - main/git
+  - { name: less, bucket: main, hold: true }
+",
+            &PlanOptions::default(),
+        );
+
+        let to_install = &plan.to_install;
+        assert_eq!(describe_apps(&to_install.scoop_apps_to_hold), ["main/7zip"]);
+        assert_eq!(
+            describe_apps(&to_install.scoop_apps_to_unhold),
+            ["main/git"]
+        );
+        assert!(to_install.scoop_apps.is_empty());
+    }
+
+    #[test]
+    fn validate_reports_undeclared_buckets_with_conflicts() {
+        let dir = TempDir::new();
+        let path = dir.path().join("main.yaml");
+        write(
+            &path,
+            "
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps: [main/git, main/git, private/tool]
+",
+        );
+
+        let err = validate(&path, &ConfigContext::current(Vec::new())).unwrap_err();
+        let problems = err.downcast_ref::<ConfigProblems>().unwrap();
+        assert_eq!(
+            problems
+                .problems
+                .iter()
+                .map(ToString::to_string)
+                .collect_vec(),
+            [
+                "app main/git is declared more than once",
+                "private/tool is in bucket private which is not declared in scoop_buckets",
+            ]
+        );
+    }
+
+    #[test]
+    fn parallel_resolution_matches_sequential() {
+        let fake = fake_backend(
+            "
+buckets:
+  main:
+    source: https://example.com/main
+    apps:
+      a: { depends: [main/b, main/c] }
+      b: { depends: [main/d] }
+      c: { depends: [main/d, main/e] }
+      d: {}
+      e: { depends: [main/f] }
+      f: {}
+      g: { depends: [main/a] }
+      h: {}
+installed_buckets: [main]
+failures: [{ operation: depends, target: main/e }]
+",
+        );
+        let config: Config = serde_yaml::from_str(
+            "
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps: [main/g, main/a, main/h, main/missing]
+",
+        )
+        .unwrap();
+        let resolve_with = |jobs| {
+            let resolve = ResolveOptions {
+                jobs: NonZeroUsize::new(jobs),
+                ..resolve_options()
+            };
+            let mut session = fake.open_session().unwrap();
+            get_required_things(
+                &mut *session,
+                &config,
+                ResolutionFailurePolicy::Keep,
+                &resolve,
+            )
+            .unwrap()
+        };
+
+        let sequential = resolve_with(1);
+        for jobs in [2, 4, 8] {
+            assert_eq!(resolve_with(jobs), sequential, "jobs = {jobs}");
+        }
+    }
+
+    #[test]
+    fn why_rejects_apps_neither_required_nor_installed() {
+        let dir = TempDir::new();
+        let path = dir.path().join("main.yaml");
+        write(&path, CONFIG);
+        let context = ConfigContext::current(Vec::new());
+
+        for app in ["main/7zip", "7zip", "main/git"] {
+            let mut fake = fake_backend(STATE);
+            let options = PlanOptions::default();
+            assert!(
+                why(
+                    &mut fake,
+                    &path,
+                    &context,
+                    app,
+                    &options,
+                    &resolve_options()
+                )
+                .is_ok()
+            );
+        }
+        // バケットを指定した場合も、省略した場合と同じく存在しないアプリケーションはエラーにする
+        for app in ["main/missing", "missing", "extras/git"] {
+            let mut fake = fake_backend(STATE);
+            let options = PlanOptions::default();
+            let err = why(
+                &mut fake,
+                &path,
+                &context,
+                app,
+                &options,
+                &resolve_options(),
+            )
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("{app} is neither required nor installed")
+            );
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::{env, fs, iter};
+
+use miette::{IntoDiagnostic, Result, WrapErr, bail, miette};
+use serde::{Deserialize, Deserializer};
+
+use crate::config::{Arch, ScoopApp};
+
+/// バケットのディレクトリにあるマニフェストを直接読んで依存関係を解決する。
+///
+/// `scoop depends` はアプリケーションごとに PowerShell 上でスクリプトを実行するため遅い。バケット
+/// は JSON のマニフェストを git で管理しているだけなので、それを直接読めば同じ結果がすぐに得られ
+/// る。scoop と同様に、`depends` に加えてインストール時の展開に必要なヘルパー (7zip など) も依存
+/// 関係とみなす。
+pub struct ManifestResolver {
+    buckets_dir: PathBuf,
+    /// インストールされているバケットの名前 (名前順)。
+    buckets: Vec<String>,
+    /// 読み込んだマニフェストの、アーキテクチャごとの直接の依存関係。
+    direct: HashMap<(ScoopApp, Arch), Vec<ScoopApp>>,
+}
+
+impl ManifestResolver {
+    /// `buckets_dir` 以下の `{バケット名}/bucket/{アプリケーション名}.json` (古い形式のバケットでは
+    /// `{バケット名}/{アプリケーション名}.json`) を読むリゾルバーを作成します。
+    pub fn new(buckets_dir: PathBuf) -> Result<Self> {
+        let mut buckets = fs::read_dir(&buckets_dir)
+            .into_diagnostic()
+            .wrap_err("failed to list buckets")?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        buckets.sort();
+
+        Ok(Self {
+            buckets_dir,
+            buckets,
+            direct: HashMap::new(),
+        })
+    }
+
+    /// scoop のインストール先のバケットを読むリゾルバーを作成します。
+    pub fn open() -> Result<Self> {
+        Self::new(buckets_dir()?)
+    }
+
+    /// `scoop depends` と同様に、アプリケーション自身を含め、間接的なものも含むすべての依存関係を
+    /// 返します。依存先も `arch` のアーキテクチャでインストールされるものとして解決します。
+    pub fn dependencies_of(&mut self, app: &ScoopApp, arch: Arch) -> Result<HashSet<ScoopApp>> {
+        let mut found = HashSet::new();
+        let mut to_visit = vec![app.clone()];
+        while let Some(app) = to_visit.pop() {
+            if found.contains(&app) {
+                continue;
+            }
+
+            to_visit.extend(self.direct_dependencies_of(&app, arch)?);
+            found.insert(app);
+        }
+
+        Ok(found)
+    }
+
+    fn direct_dependencies_of(&mut self, app: &ScoopApp, arch: Arch) -> Result<Vec<ScoopApp>> {
+        let key = (app.clone(), arch);
+        if let Some(dependencies) = self.direct.get(&key) {
+            return Ok(dependencies.clone());
+        }
+
+        let dependencies = self
+            .read_manifest(app)?
+            .dependencies(arch)
+            .iter()
+            .map(|name| self.qualify(name, &app.bucket_name))
+            .collect::<Result<Vec<_>>>()
+            .wrap_err_with(|| format!("failed to resolve dependencies of {app}"))?;
+        self.direct.insert(key, dependencies.clone());
+
+        Ok(dependencies)
+    }
+
+    fn manifest_path(&self, app: &ScoopApp) -> Option<PathBuf> {
+        let bucket_dir = self.buckets_dir.join(&app.bucket_name);
+        let file_name = format!("{}.json", app.name);
+
+        [bucket_dir.join("bucket"), bucket_dir]
+            .into_iter()
+            .map(|dir| dir.join(&file_name))
+            .find(|path| path.is_file())
+    }
+
+    fn read_manifest(&self, app: &ScoopApp) -> Result<Manifest> {
+        let Some(path) = self.manifest_path(app) else {
+            bail!("Couldn't find manifest for {app}");
+        };
+
+        let text = fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to read manifest {}", path.display()))?;
+        serde_json::from_str(&text)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to parse manifest {}", path.display()))
+    }
+
+    /// 依存関係に書かれた名前をバケット付きの名前にします。バケットが省略されていれば、依存元と同
+    /// じバケット、その他のバケットの順 (名前順) に探します。
+    fn qualify(&self, name: &str, bucket_name: &str) -> Result<ScoopApp> {
+        if name.contains('/') {
+            return name.parse().map_err(|e: String| miette!(e));
+        }
+
+        iter::once(bucket_name)
+            .chain(self.buckets.iter().map(String::as_str))
+            .map(|bucket_name| ScoopApp {
+                name: name.to_string(),
+                bucket_name: bucket_name.to_string(),
+            })
+            .find(|app| self.manifest_path(app).is_some())
+            .ok_or_else(|| miette!("Couldn't find manifest for {name}"))
+    }
+}
+
+/// scoop のインストール先 (環境変数 `SCOOP`、なければホームディレクトリの `scoop`) にあるバケッ
+/// トのディレクトリを返します。
+pub fn buckets_dir() -> Result<PathBuf> {
+    let root = match env::var_os("SCOOP") {
+        Some(root) => PathBuf::from(root),
+        None => env::var_os("USERPROFILE")
+            .or_else(|| env::var_os("HOME"))
+            .map(|home| Path::new(&home).join("scoop"))
+            .ok_or_else(|| miette!("failed to find the home directory"))?,
+    };
+
+    let buckets_dir = root.join("buckets");
+    if !buckets_dir.is_dir() {
+        bail!("buckets directory not found: {}", buckets_dir.display());
+    }
+
+    Ok(buckets_dir)
+}
+
+/// マニフェストのうち、依存関係に関係する項目。
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Manifest {
+    #[serde(flatten)]
+    common: ArchSpecific,
+    innosetup: bool,
+    architecture: HashMap<String, ArchSpecific>,
+}
+
+/// マニフェストの、アーキテクチャごとに上書きできる項目。
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ArchSpecific {
+    #[serde(deserialize_with = "one_or_many")]
+    depends: Vec<String>,
+    #[serde(deserialize_with = "one_or_many")]
+    url: Vec<String>,
+    installer: Option<Installer>,
+    #[serde(deserialize_with = "one_or_many")]
+    pre_install: Vec<String>,
+    #[serde(deserialize_with = "one_or_many")]
+    post_install: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Installer {
+    #[serde(deserialize_with = "one_or_many")]
+    script: Vec<String>,
+}
+
+impl Manifest {
+    /// 直接の依存関係の名前を返します。
+    fn dependencies(&self, arch: Arch) -> Vec<String> {
+        // scoop は指定のアーキテクチャがマニフェストになければ、互換性のあるものを使う
+        let fallbacks: &[Arch] = match arch {
+            Arch::X64 => &[Arch::X64, Arch::X86],
+            Arch::X86 => &[Arch::X86],
+            Arch::Arm64 => &[Arch::Arm64, Arch::X64, Arch::X86],
+        };
+        let specific = fallbacks
+            .iter()
+            .find_map(|arch| self.architecture.get(arch.as_str()));
+
+        // url やスクリプトはアーキテクチャごとの指定で上書きされる
+        let pick = |field: fn(&ArchSpecific) -> &[String]| match specific {
+            Some(specific) if !field(specific).is_empty() => field(specific),
+            _ => field(&self.common),
+        };
+        let urls = pick(|m| &m.url);
+        let scripts: Vec<&String> = [
+            pick(|m| m.installer.as_ref().map_or(&[], |i| &i.script)),
+            pick(|m| &m.pre_install),
+            pick(|m| &m.post_install),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let uses = |command: &str| {
+            scripts
+                .iter()
+                .any(|line| line.contains(&format!("{command} ")))
+        };
+
+        let mut dependencies = self.common.depends.clone();
+        if let Some(specific) = specific {
+            dependencies.extend(specific.depends.iter().cloned());
+        }
+
+        // scoop の Get-InstallationHelper と同じ判定。lessmsi は既定の設定では使われないので含めない
+        if urls.iter().any(|url| requires_7zip(url)) || uses("Expand-7zipArchive") {
+            dependencies.push("7zip".to_string());
+        }
+        if self.innosetup || uses("Expand-InnoArchive") {
+            dependencies.push("innounp".to_string());
+        }
+        if uses("Expand-DarkArchive") {
+            dependencies.push("dark".to_string());
+        }
+
+        dependencies
+    }
+}
+
+/// 展開に 7zip が必要な形式の URL かどうかを返します。
+fn requires_7zip(url: &str) -> bool {
+    const EXTENSIONS: &[&str] = &[
+        "001", "7z", "bz", "bz2", "bzip2", "gz", "img", "iso", "lzma", "lzh", "nupkg", "rar",
+        "tar", "tbz", "tbz2", "tgz", "tpz", "txz", "tzst", "xz", "zst",
+    ];
+
+    url.rsplit_once('.')
+        .is_some_and(|(_, ext)| EXTENSIONS.contains(&&*ext.to_ascii_lowercase()))
+}
+
+/// 文字列一つか文字列の配列のどちらでも書ける項目を読みます。
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::app;
+
+    fn resolver() -> ManifestResolver {
+        let buckets_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/buckets");
+        ManifestResolver::new(buckets_dir).unwrap()
+    }
+
+    fn dependencies(id: &str, arch: Arch) -> HashSet<ScoopApp> {
+        resolver().dependencies_of(&app(id), arch).unwrap()
+    }
+
+    fn apps(ids: &[&str]) -> HashSet<ScoopApp> {
+        ids.iter().map(|id| app(id)).collect()
+    }
+
+    #[test]
+    fn reads_depends_as_string_or_array() {
+        assert_eq!(
+            dependencies("main/single", Arch::X64),
+            apps(&["main/single", "main/lib"])
+        );
+        assert_eq!(
+            dependencies("main/multiple", Arch::X64),
+            apps(&["main/multiple", "main/lib", "extras/tool"])
+        );
+    }
+
+    #[test]
+    fn falls_back_to_compatible_architecture() {
+        assert_eq!(
+            dependencies("main/per-arch", Arch::X64),
+            apps(&["main/per-arch", "main/x64-only"])
+        );
+        assert_eq!(
+            dependencies("main/per-arch", Arch::X86),
+            apps(&["main/per-arch", "main/x86-only"])
+        );
+        // arm64 の指定がなければ 64bit のものを使う
+        assert_eq!(
+            dependencies("main/per-arch", Arch::Arm64),
+            apps(&["main/per-arch", "main/x64-only"])
+        );
+    }
+
+    #[test]
+    fn caches_dependencies_per_architecture() {
+        let mut resolver = resolver();
+        let x64 = resolver.dependencies_of(&app("main/per-arch"), Arch::X64);
+        let x86 = resolver.dependencies_of(&app("main/per-arch"), Arch::X86);
+        assert!(x64.unwrap().contains(&app("main/x64-only")));
+        assert!(x86.unwrap().contains(&app("main/x86-only")));
+    }
+
+    #[test]
+    fn detects_installation_helpers() {
+        assert_eq!(
+            dependencies("main/archive", Arch::X64),
+            apps(&["main/archive", "main/7zip"])
+        );
+        assert_eq!(
+            dependencies("main/inno", Arch::X64),
+            apps(&["main/inno", "main/innounp"])
+        );
+        assert_eq!(
+            dependencies("main/wix", Arch::X64),
+            apps(&["main/wix", "main/dark"])
+        );
+    }
+
+    #[test]
+    fn resolves_dependencies_across_buckets() {
+        // 依存元のバケットになければ、他のバケットから探す
+        assert_eq!(
+            dependencies("extras/tool", Arch::X64),
+            apps(&["extras/tool", "main/lib"])
+        );
+        // 古い形式のバケットはマニフェストがバケットの直下にある
+        assert_eq!(
+            dependencies("legacy/old", Arch::X64),
+            apps(&["legacy/old", "extras/tool", "main/lib"])
+        );
+    }
+
+    #[test]
+    fn reports_missing_manifest() {
+        let error = resolver()
+            .dependencies_of(&app("main/missing"), Arch::X64)
+            .unwrap_err();
+        assert!(error.to_string().contains("main/missing"));
+    }
+}
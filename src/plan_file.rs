@@ -0,0 +1,190 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use miette::{IntoDiagnostic, Result, WrapErr, bail, miette};
+use serde::{Deserialize, Serialize};
+
+use crate::{InstalledThings, ThingsToInstall, ThingsToUninstall};
+
+/// 現在のプランファイルの形式のバージョン。形式を変更したら上げること。
+const PLAN_FILE_VERSION: u32 = 7;
+
+/// ファイルに保存された実行計画。
+///
+/// 計画を作成した時点のインストール状態も一緒に保存しておき、適用時に状態が変わっていれば適用を
+/// 拒否する。レビューした内容とは異なる変更が行われることを防ぐため。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanFile {
+    pub version: u32,
+    pub installed: InstalledThings,
+    pub to_uninstall: ThingsToUninstall,
+    pub to_install: ThingsToInstall,
+}
+
+impl PlanFile {
+    pub fn new(
+        installed: InstalledThings,
+        to_uninstall: ThingsToUninstall,
+        to_install: ThingsToInstall,
+    ) -> Self {
+        Self {
+            version: PLAN_FILE_VERSION,
+            installed,
+            to_uninstall,
+            to_install,
+        }
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self)
+            .into_diagnostic()
+            .wrap_err("failed to serialize plan")?;
+
+        fs::write(path, json)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write plan to file {path}", path = path.display()))
+    }
+
+    pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .into_diagnostic()
+            .wrap_err_with(|| {
+                miette!(
+                    "failed to read plan from file {path}",
+                    path = path.display()
+                )
+            })?;
+        let plan: PlanFile = serde_json::from_str(&json)
+            .into_diagnostic()
+            .wrap_err_with(|| {
+                miette!(
+                    "failed to parse plan from file {path}",
+                    path = path.display()
+                )
+            })?;
+
+        if plan.version != PLAN_FILE_VERSION {
+            bail!(
+                "unsupported plan file version {} (expected {PLAN_FILE_VERSION})",
+                plan.version
+            );
+        }
+
+        Ok(plan)
+    }
+
+    /// 計画作成時から現在のインストール状態が変わっていないことを確認します。
+    pub fn ensure_up_to_date(&self, current: &InstalledThings) -> Result<()> {
+        // バケットの順序は scoop の出力次第なので集合として比較する
+        let planned_buckets: HashSet<_> = self.installed.scoop_buckets.iter().collect();
+        let current_buckets: HashSet<_> = current.scoop_buckets.iter().collect();
+
+        if planned_buckets != current_buckets
+            || self.installed.scoop_apps != current.scoop_apps
+            || self.installed.global_scoop_apps != current.global_scoop_apps
+        {
+            bail!(
+                "installed applications have changed since the plan was created; create the plan again"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InstalledApp;
+    use crate::config::{Arch, ScoopBucket};
+    use crate::test_util::app;
+
+    fn installed(
+        buckets: &[&str],
+        apps: &[(&str, &str)],
+        global_apps: &[(&str, &str)],
+    ) -> InstalledThings {
+        let installed_apps = |apps: &[(&str, &str)]| {
+            apps.iter()
+                .map(|(id, version)| {
+                    let installed = InstalledApp {
+                        version: version.to_string(),
+                        arch: Arch::X64,
+                        held: false,
+                    };
+                    (app(id), installed)
+                })
+                .collect()
+        };
+
+        InstalledThings {
+            scoop_buckets: buckets
+                .iter()
+                .map(|name| ScoopBucket {
+                    name: name.to_string(),
+                    source: format!("https://example.com/{name}"),
+                })
+                .collect(),
+            scoop_apps: installed_apps(apps),
+            global_scoop_apps: installed_apps(global_apps),
+        }
+    }
+
+    fn plan_file(installed: InstalledThings) -> PlanFile {
+        let to_uninstall = ThingsToUninstall {
+            scoop_buckets: HashSet::new(),
+            scoop_apps: HashSet::new(),
+            kept_scoop_buckets: HashSet::new(),
+            kept_scoop_apps: HashSet::new(),
+        };
+        let to_install = ThingsToInstall {
+            scoop_buckets: HashSet::new(),
+            scoop_apps: HashSet::new(),
+            scoop_apps_to_reinstall: HashSet::new(),
+            scoop_apps_to_upgrade: HashSet::new(),
+            scoop_apps_to_hold: HashSet::new(),
+            scoop_apps_to_unhold: HashSet::new(),
+        };
+        PlanFile::new(installed, to_uninstall, to_install)
+    }
+
+    #[test]
+    fn accepts_unchanged_state_regardless_of_bucket_order() {
+        let saved = plan_file(installed(
+            &["main", "extras"],
+            &[("main/git", "2.44.0")],
+            &[],
+        ));
+        let current = installed(&["extras", "main"], &[("main/git", "2.44.0")], &[]);
+
+        assert!(saved.ensure_up_to_date(&current).is_ok());
+    }
+
+    #[test]
+    fn rejects_stale_plan() {
+        let saved = plan_file(installed(&["main"], &[("main/git", "2.44.0")], &[]));
+
+        for current in [
+            installed(&["main", "extras"], &[("main/git", "2.44.0")], &[]),
+            installed(&["main"], &[("main/git", "2.45.0")], &[]),
+            installed(
+                &["main"],
+                &[("main/git", "2.44.0"), ("main/7zip", "23.01")],
+                &[],
+            ),
+            installed(
+                &["main"],
+                &[("main/git", "2.44.0")],
+                &[("main/7zip", "23.01")],
+            ),
+            installed(&["main"], &[], &[]),
+        ] {
+            let err = saved.ensure_up_to_date(&current).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "installed applications have changed since the plan was created; create the plan again"
+            );
+        }
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use itertools::Itertools;
+use miette::{Diagnostic, Result, bail};
+use thiserror::Error;
+
+use crate::config::{Config, ScoopApp, ScoopBucket};
+use crate::{
+    InstalledThings, RequiredThings, ThingsToInstall, ThingsToUninstall, describe_scope,
+    make_warning_label,
+};
+
+/// 設定ファイルに記載されたアプリケーションとバケットの整合性の問題。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BucketIssue {
+    /// `scoop_buckets` に宣言されていない (既知のバケットでもない) バケットのアプリケーションが記
+    /// 載されている。
+    Undeclared {
+        app: ScoopApp,
+        /// 無視するパターンや追加のみのモードで、インストール済みのバケットが残されるかどうか。
+        kept: bool,
+    },
+    /// どのアプリケーションからも使われていないバケットが宣言されている。
+    Unused { bucket: String },
+    /// 依存関係が、宣言されていない (削除される) バケットのアプリケーションを必要としている。
+    RemovedDependency {
+        app: ScoopApp,
+        dependency: ScoopApp,
+        /// 無視するパターンや追加のみのモードで、バケットが削除されずに残るかどうか。
+        kept: bool,
+    },
+}
+
+impl BucketIssue {
+    /// 計画を作成できない問題かどうかを返します。
+    pub fn is_error(&self) -> bool {
+        match self {
+            BucketIssue::Undeclared { kept, .. } => !kept,
+            BucketIssue::Unused { .. } => false,
+            BucketIssue::RemovedDependency { kept, .. } => !kept,
+        }
+    }
+}
+
+impl fmt::Display for BucketIssue {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BucketIssue::Undeclared { app, .. } => write!(
+                b,
+                "{app} is in bucket {} which is not declared in scoop_buckets",
+                app.bucket_name
+            ),
+            BucketIssue::Unused { bucket } => {
+                write!(b, "bucket {bucket} is declared but no app uses it")
+            }
+            BucketIssue::RemovedDependency {
+                app,
+                dependency,
+                kept: false,
+            } => write!(
+                b,
+                "{app} depends on {dependency}, but bucket {} is not declared and will be removed",
+                dependency.bucket_name
+            ),
+            BucketIssue::RemovedDependency {
+                app,
+                dependency,
+                kept: true,
+            } => write!(
+                b,
+                "{app} depends on {dependency}, but bucket {} is not declared",
+                dependency.bucket_name
+            ),
+        }
+    }
+}
+
+/// 依存関係を解決する前に、設定ファイルに記載されたアプリケーションのバケットが宣言されているか
+/// を確認します。`additive` は宣言されていないバケットを削除しないかどうか。
+pub fn check_declared_buckets(config: &Config, additive: bool) -> Vec<BucketIssue> {
+    let declared: HashSet<&str> = config
+        .scoop_buckets
+        .iter()
+        .map(|bucket| &*bucket.name)
+        .collect();
+
+    config
+        .scoop_apps
+        .iter()
+        .filter(|spec| {
+            let bucket = &*spec.app.bucket_name;
+            !declared.contains(bucket) && ScoopBucket::known(bucket).is_none()
+        })
+        .map(|spec| BucketIssue::Undeclared {
+            app: spec.app.clone(),
+            kept: additive || config.ignore.ignores_bucket(&spec.app.bucket_name),
+        })
+        .collect()
+}
+
+/// 依存関係を解決した後に、使われていないバケットと、削除されるバケットに依存しているアプリケー
+/// ションを確認します。`additive` は宣言されていないバケットを削除しないかどうか。
+pub fn check_resolved_buckets(
+    config: &Config,
+    required: &RequiredThings,
+    additive: bool,
+) -> Vec<BucketIssue> {
+    // 既知のバケットは使われていれば必要なバケットに加わっているので、それも宣言されたものとみなす
+    let declared: HashSet<&str> = required
+        .scoop_buckets
+        .iter()
+        .map(|bucket| &*bucket.name)
+        .collect();
+    let mut issues = Vec::new();
+
+    for (app, deps) in required
+        .scoop_apps
+        .iter()
+        .sorted_by_key(|(app, _)| app.to_string())
+    {
+        for dependency in deps.iter().sorted_by_key(|dep| dep.to_string()) {
+            if dependency == app || declared.contains(&*dependency.bucket_name) {
+                continue;
+            }
+
+            issues.push(BucketIssue::RemovedDependency {
+                app: app.clone(),
+                dependency: dependency.clone(),
+                kept: additive || config.ignore.ignores_bucket(&dependency.bucket_name),
+            });
+        }
+    }
+
+    // 解決に失敗したアプリケーションは required に含まれないので、設定ファイルに記載されたものも
+    // 使われているとみなす
+    let used: HashSet<&str> = required
+        .scoop_apps
+        .keys()
+        .chain(config.scoop_apps.iter().map(|spec| &spec.app))
+        .map(|app| &*app.bucket_name)
+        .collect();
+    for bucket in &config.scoop_buckets {
+        if !used.contains(&*bucket.name) {
+            issues.push(BucketIssue::Unused {
+                bucket: bucket.name.clone(),
+            });
+        }
+    }
+
+    issues
+}
+
+/// 見つかった問題を表示します。計画を作成できない問題があればエラーを返します。
+pub fn report_bucket_issues(issues: &[BucketIssue]) -> Result<()> {
+    for issue in issues.iter().filter(|issue| !issue.is_error()) {
+        eprintln!("{} {issue}", make_warning_label());
+    }
+
+    let errors = issues.iter().filter(|issue| issue.is_error()).collect_vec();
+    if !errors.is_empty() {
+        bail!(
+            "bucket consistency check failed:\n{}",
+            errors.iter().map(|issue| format!("  - {issue}")).join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// 解決した依存関係とインストールする項目に見つかった、計画を実行できない問題。
+#[derive(Debug, Clone, PartialEq, Eq, Error, Diagnostic)]
+pub enum DependencyIssue {
+    /// アプリケーションが互いに依存している。
+    #[error("dependency cycle between {}", .apps.iter().join(", "))]
+    #[diagnostic(
+        code(dependency::cycle),
+        help("scoop refuses to install apps with circular dependencies")
+    )]
+    Cycle { apps: Vec<ScoopApp> },
+    /// 同じ名前のアプリケーションが異なるバケットから必要とされている。
+    #[error(
+        "{name}{} is required from more than one bucket: {}",
+        if *.global { " [global]" } else { "" },
+        .providers.iter().join("; ")
+    )]
+    #[diagnostic(
+        code(dependency::same_name),
+        help("scoop installs apps by name, so only one of them can be installed")
+    )]
+    SameName {
+        name: String,
+        global: bool,
+        providers: Vec<Provider>,
+    },
+    /// 同じ名前のアプリケーションが別のバケットからインストール済みで、それが残される。
+    #[error(
+        "{} would be installed, but {installed} is already installed and kept",
+        describe_scope(.app, *.global)
+    )]
+    #[diagnostic(
+        code(dependency::installed_from_other_bucket),
+        help("uninstall {installed} first, or use it in the configuration instead")
+    )]
+    InstalledFromOtherBucket {
+        app: ScoopApp,
+        installed: ScoopApp,
+        global: bool,
+        /// 追加のみのモードで、インストール済みのものを意図して残しているかどうか。
+        additive: bool,
+    },
+}
+
+impl DependencyIssue {
+    /// 計画を作成できない問題かどうかを返します。
+    pub fn is_error(&self) -> bool {
+        match self {
+            DependencyIssue::Cycle { .. } | DependencyIssue::SameName { .. } => true,
+            DependencyIssue::InstalledFromOtherBucket { additive, .. } => !additive,
+        }
+    }
+}
+
+/// 同じ名前のアプリケーションのうちの一つと、それを必要としている設定ファイルのアプリケーション。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provider {
+    pub app: ScoopApp,
+    pub required_by: Vec<ScoopApp>,
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match &*self.required_by {
+            [only] if *only == self.app => write!(b, "{} (in the configuration)", self.app),
+            required_by => write!(
+                b,
+                "{} (required by {})",
+                self.app,
+                required_by.iter().join(", ")
+            ),
+        }
+    }
+}
+
+/// 依存関係の問題をまとめたエラー。
+#[derive(Debug, Error, Diagnostic)]
+#[error("found {} problem(s) in the resolved dependencies", issues.len())]
+pub struct DependencyProblems {
+    #[related]
+    pub issues: Vec<DependencyIssue>,
+}
+
+/// 依存関係の循環と、同じ名前のアプリケーションが複数のバケットから必要とされていないかを確認し
+/// ます。
+pub fn check_dependencies(required: &RequiredThings) -> Vec<DependencyIssue> {
+    let mut issues = Vec::new();
+
+    // 解決結果は間接的なものも含むので、互いに相手を含んでいれば循環している
+    let mut cycles = HashSet::new();
+    for (app, deps) in &required.scoop_apps {
+        let cycle = deps
+            .iter()
+            .filter(|dep| {
+                required
+                    .scoop_apps
+                    .get(dep)
+                    .is_some_and(|dep_deps| dep_deps.contains(app))
+            })
+            .chain([app])
+            .unique()
+            .cloned()
+            .sorted_by_key(|app| app.to_string())
+            .collect_vec();
+        if cycle.len() > 1 {
+            cycles.insert(cycle);
+        }
+    }
+    issues.extend(
+        cycles
+            .into_iter()
+            .sorted_by_key(|apps| apps[0].to_string())
+            .map(|apps| DependencyIssue::Cycle { apps }),
+    );
+
+    for global in [false, true] {
+        let apps = if global {
+            &required.global_apps
+        } else {
+            &required.user_apps
+        };
+        let by_name: BTreeMap<&str, Vec<&ScoopApp>> = apps
+            .iter()
+            .into_group_map_by(|app| &*app.name)
+            .into_iter()
+            .collect();
+
+        for (name, apps) in by_name {
+            if apps.len() < 2 {
+                continue;
+            }
+
+            let providers = apps
+                .into_iter()
+                .sorted_by_key(|app| app.to_string())
+                .map(|app| Provider {
+                    app: app.clone(),
+                    required_by: required
+                        .app_specs
+                        .values()
+                        .filter(|spec| spec.global == global)
+                        .filter(|spec| {
+                            required
+                                .scoop_apps
+                                .get(&spec.app)
+                                .is_some_and(|deps| spec.app == *app || deps.contains(app))
+                        })
+                        .map(|spec| spec.app.clone())
+                        .sorted_by_key(|app| app.to_string())
+                        .collect(),
+                })
+                .collect();
+            issues.push(DependencyIssue::SameName {
+                name: name.to_string(),
+                global,
+                providers,
+            });
+        }
+    }
+
+    issues
+}
+
+/// インストールするアプリケーションと同じ名前のアプリケーションが、別のバケットからインストール
+/// 済みで残されないかを確認します。残されるものがあると scoop はインストールを拒否する。
+/// `additive` は追加のみのモードかどうか。
+pub fn check_installed_sources(
+    installed: &InstalledThings,
+    to_uninstall: &ThingsToUninstall,
+    to_install: &ThingsToInstall,
+    additive: bool,
+) -> Vec<DependencyIssue> {
+    to_install
+        .scoop_apps
+        .iter()
+        .sorted_by_key(|spec| (spec.app.to_string(), spec.global))
+        .flat_map(|spec| {
+            installed
+                .apps()
+                .filter(move |(app, global, _)| {
+                    *global == spec.global
+                        && app.name == spec.app.name
+                        && app.bucket_name != spec.app.bucket_name
+                })
+                .filter(|(app, global, _)| {
+                    !to_uninstall
+                        .scoop_apps
+                        .iter()
+                        .any(|removed| removed.app == **app && removed.global == *global)
+                })
+                .map(
+                    |(app, global, _)| DependencyIssue::InstalledFromOtherBucket {
+                        app: spec.app.clone(),
+                        installed: app.clone(),
+                        global,
+                        additive,
+                    },
+                )
+        })
+        .collect()
+}
+
+/// 見つかった依存関係の問題を表示します。計画を作成できない問題があればエラーを返します。
+pub fn report_dependency_issues(issues: Vec<DependencyIssue>) -> Result<()> {
+    let (errors, warnings): (Vec<_>, Vec<_>) =
+        issues.into_iter().partition(|issue| issue.is_error());
+    for issue in warnings {
+        eprintln!("{} {issue}", make_warning_label());
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(DependencyProblems { issues: errors }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::InstalledApp;
+    use crate::config::{AppSpec, Arch};
+    use crate::test_util::{app, config, required};
+
+    #[test]
+    fn undeclared_bucket_is_error() {
+        let config = config("scoop_apps: [custom/foo, main/git]");
+        let issues = check_declared_buckets(&config, false);
+
+        // main は既知のバケットなので宣言しなくてよい
+        assert_eq!(
+            issues,
+            [BucketIssue::Undeclared {
+                app: app("custom/foo"),
+                kept: false,
+            }]
+        );
+        assert!(issues[0].is_error());
+    }
+
+    #[test]
+    fn undeclared_bucket_is_warning_when_kept() {
+        let issues = check_declared_buckets(&config("scoop_apps: [custom/foo]"), true);
+        assert!(!issues[0].is_error());
+
+        // 無視するパターンに一致するバケットも削除されない
+        let config = config(
+            "
+scoop_apps: [custom/foo]
+ignore: { scoop_buckets: [cust*] }
+",
+        );
+        let issues = check_declared_buckets(&config, false);
+        assert!(!issues[0].is_error());
+    }
+
+    #[test]
+    fn unused_bucket_is_warning() {
+        let config = config(
+            "
+scoop_buckets:
+  - { name: main, source: https://example.com/main }
+  - { name: tools, source: https://example.com/tools }
+scoop_apps: [main/git]
+",
+        );
+        let required = required(&config, &[("main/git", &["main/git"])]);
+        let issues = check_resolved_buckets(&config, &required, false);
+
+        assert_eq!(
+            issues,
+            [BucketIssue::Unused {
+                bucket: "tools".to_string(),
+            }]
+        );
+        assert!(!issues[0].is_error());
+    }
+
+    #[test]
+    fn dependency_on_removed_bucket() {
+        let yaml = "
+scoop_buckets: [{ name: my, source: https://example.com/my }]
+scoop_apps: [my/foo]
+";
+        let resolved: &[(&str, &[&str])] = &[
+            ("my/foo", &["my/foo", "other/bar"]),
+            ("other/bar", &["other/bar"]),
+        ];
+        let config = config(yaml);
+        let issues = check_resolved_buckets(&config, &required(&config, resolved), false);
+        assert_eq!(
+            issues,
+            [BucketIssue::RemovedDependency {
+                app: app("my/foo"),
+                dependency: app("other/bar"),
+                kept: false,
+            }]
+        );
+        assert!(issues[0].is_error());
+
+        // 追加のみのモードではバケットは削除されない
+        let issues = check_resolved_buckets(&config, &required(&config, resolved), true);
+        assert!(!issues[0].is_error());
+    }
+
+    #[test]
+    fn detects_dependency_cycle() {
+        let config = config("scoop_apps: [main/a, main/c]");
+        let required = required(
+            &config,
+            &[
+                ("main/a", &["main/a", "main/b"]),
+                ("main/b", &["main/b", "main/a"]),
+                ("main/c", &["main/c"]),
+            ],
+        );
+
+        // 循環に含まれるアプリケーションそれぞれから見つかるが、報告は一度だけ
+        let issues = check_dependencies(&required);
+        assert_eq!(
+            issues,
+            [DependencyIssue::Cycle {
+                apps: vec![app("main/a"), app("main/b")],
+            }]
+        );
+        assert!(issues[0].is_error());
+    }
+
+    #[test]
+    fn detects_same_name_from_different_buckets() {
+        let config = config("scoop_apps: [main/foo, extras/bar]");
+        let required = required(
+            &config,
+            &[
+                ("main/foo", &["main/foo"]),
+                ("extras/bar", &["extras/bar", "extras/foo"]),
+                ("extras/foo", &["extras/foo"]),
+            ],
+        );
+
+        assert_eq!(
+            check_dependencies(&required),
+            [DependencyIssue::SameName {
+                name: "foo".to_string(),
+                global: false,
+                providers: vec![
+                    Provider {
+                        app: app("extras/foo"),
+                        required_by: vec![app("extras/bar")],
+                    },
+                    Provider {
+                        app: app("main/foo"),
+                        required_by: vec![app("main/foo")],
+                    },
+                ],
+            }]
+        );
+
+        // global とユーザー単位には別々にインストールできる
+        let config = self::config(
+            "
+scoop_apps:
+  - main/foo
+  - { name: extras/foo, global: true }
+",
+        );
+        let required = self::required(
+            &config,
+            &[("main/foo", &["main/foo"]), ("extras/foo", &["extras/foo"])],
+        );
+        assert_eq!(check_dependencies(&required), []);
+    }
+
+    fn spec(id: &str) -> AppSpec {
+        config(&format!("scoop_apps: [{id}]")).scoop_apps[0].clone()
+    }
+
+    #[test]
+    fn detects_app_installed_from_other_bucket() {
+        let installed = InstalledThings {
+            scoop_buckets: Vec::new(),
+            scoop_apps: HashMap::from([(
+                app("extras/foo"),
+                InstalledApp {
+                    version: "1.0".to_string(),
+                    arch: Arch::X64,
+                    held: false,
+                },
+            )]),
+            global_scoop_apps: HashMap::new(),
+        };
+        let mut to_uninstall = ThingsToUninstall {
+            scoop_buckets: HashSet::new(),
+            scoop_apps: HashSet::new(),
+            kept_scoop_buckets: HashSet::new(),
+            kept_scoop_apps: HashSet::from([spec("extras/foo")]),
+        };
+        let to_install = ThingsToInstall {
+            scoop_buckets: HashSet::new(),
+            scoop_apps: HashSet::from([spec("main/foo")]),
+            scoop_apps_to_reinstall: HashSet::new(),
+            scoop_apps_to_upgrade: HashSet::new(),
+            scoop_apps_to_hold: HashSet::new(),
+            scoop_apps_to_unhold: HashSet::new(),
+        };
+
+        let issues = check_installed_sources(&installed, &to_uninstall, &to_install, false);
+        assert_eq!(
+            issues,
+            [DependencyIssue::InstalledFromOtherBucket {
+                app: app("main/foo"),
+                installed: app("extras/foo"),
+                global: false,
+                additive: false,
+            }]
+        );
+        assert!(issues[0].is_error());
+
+        // 追加のみのモードでは意図して残しているので警告にとどめる
+        let issues = check_installed_sources(&installed, &to_uninstall, &to_install, true);
+        assert!(!issues[0].is_error());
+
+        // 先にアンインストールされるなら問題ない
+        to_uninstall.scoop_apps = to_uninstall.kept_scoop_apps.drain().collect();
+        assert_eq!(
+            check_installed_sources(&installed, &to_uninstall, &to_install, false),
+            []
+        );
+    }
+}
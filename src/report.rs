@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Serialize;
+
+use crate::config::{AppSpec, Arch, Config, ScoopApp, ScoopBucket};
+use crate::{RequiredThings, ThingsToInstall, ThingsToUninstall};
+
+/// 機械可読な形式で出力するための実行計画。
+///
+/// ダッシュボードやレビュー用のボットなど、外部のツールが読むことを想定している。出力が実行ごとに
+/// 変わらないよう、すべての項目は名前順に並べる。
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanReport {
+    pub install: InstallReport,
+    pub uninstall: UninstallReport,
+    /// 解決された依存関係 (アプリケーション → 自身と間接的なものも含む依存関係)。
+    pub dependencies: BTreeMap<String, Vec<ScoopApp>>,
+    /// 依存関係を解決できなかったアプリケーション。
+    pub unresolved: Vec<UnresolvedApp>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InstallReport {
+    pub buckets: Vec<ScoopBucket>,
+    pub apps: Vec<RequiredApp>,
+    pub reinstall: Vec<ReinstalledApp>,
+    pub upgrade: Vec<UpgradedApp>,
+    pub hold: Vec<ScopedApp>,
+    pub unhold: Vec<ScopedApp>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UninstallReport {
+    pub buckets: Vec<ScoopBucket>,
+    pub apps: Vec<ScopedApp>,
+    /// アンインストールせずに残すバケット。
+    pub kept_buckets: Vec<ScoopBucket>,
+    /// アンインストールせずに残すアプリケーション。
+    pub kept_apps: Vec<ScopedApp>,
+}
+
+/// アンインストールや固定など、スコープだけが意味を持つ操作の対象となるアプリケーション。
+#[derive(Debug, Clone, Serialize)]
+pub struct ScopedApp {
+    pub app: ScoopApp,
+    pub global: bool,
+}
+
+/// インストールされるアプリケーションと、それが必要な理由。
+#[derive(Debug, Clone, Serialize)]
+pub struct RequiredApp {
+    pub app: ScoopApp,
+    /// 固定されたバージョン。
+    pub version: Option<String>,
+    pub arch: Option<Arch>,
+    pub global: bool,
+    pub hold: bool,
+    /// 設定ファイルに直接記載されているかどうか。
+    pub explicit: bool,
+    /// このアプリケーションに依存しているアプリケーション。
+    pub required_by: Vec<ScoopApp>,
+}
+
+/// 固定されたバージョンやアーキテクチャで入れ直されるアプリケーション。
+#[derive(Debug, Clone, Serialize)]
+pub struct ReinstalledApp {
+    pub app: ScoopApp,
+    pub installed_version: String,
+    pub version: Option<String>,
+    pub installed_arch: Arch,
+    pub arch: Option<Arch>,
+    pub global: bool,
+}
+
+/// 最新のバージョンに更新されるアプリケーション。
+#[derive(Debug, Clone, Serialize)]
+pub struct UpgradedApp {
+    pub app: ScoopApp,
+    pub global: bool,
+    pub installed_version: String,
+    pub latest_version: String,
+}
+
+/// 依存関係を解決できなかったアプリケーション。
+#[derive(Debug, Clone, Serialize)]
+pub struct UnresolvedApp {
+    pub app: ScoopApp,
+    pub optional: bool,
+    pub error: String,
+}
+
+impl PlanReport {
+    pub fn new(
+        config: &Config,
+        required: &RequiredThings,
+        to_uninstall: &ThingsToUninstall,
+        to_install: &ThingsToInstall,
+    ) -> Self {
+        let required_by = |app: &ScoopApp| {
+            required
+                .scoop_apps
+                .iter()
+                .filter(|(dependent, deps)| *dependent != app && deps.contains(app))
+                .map(|(dependent, _)| dependent.clone())
+                .sorted_by_key(|dependent| dependent.to_string())
+                .collect()
+        };
+
+        PlanReport {
+            install: InstallReport {
+                buckets: sorted_buckets(&to_install.scoop_buckets),
+                apps: to_install
+                    .scoop_apps
+                    .iter()
+                    .sorted_by_key(|spec| (spec.app.to_string(), spec.global))
+                    .map(|spec| RequiredApp {
+                        app: spec.app.clone(),
+                        version: spec.version.clone(),
+                        arch: spec.arch,
+                        global: spec.global,
+                        hold: spec.hold,
+                        explicit: config.scoop_apps.iter().any(|s| s.app == spec.app),
+                        required_by: required_by(&spec.app),
+                    })
+                    .collect(),
+                reinstall: to_install
+                    .scoop_apps_to_reinstall
+                    .iter()
+                    .sorted_by_key(|reinstall| {
+                        (reinstall.spec.app.to_string(), reinstall.spec.global)
+                    })
+                    .map(|reinstall| ReinstalledApp {
+                        app: reinstall.spec.app.clone(),
+                        installed_version: reinstall.installed_version.clone(),
+                        version: reinstall.spec.version.clone(),
+                        installed_arch: reinstall.installed_arch,
+                        arch: reinstall.spec.arch,
+                        global: reinstall.spec.global,
+                    })
+                    .collect(),
+                upgrade: to_install
+                    .scoop_apps_to_upgrade
+                    .iter()
+                    .sorted_by_key(|upgrade| (upgrade.app.to_string(), upgrade.global))
+                    .map(|upgrade| UpgradedApp {
+                        app: upgrade.app.clone(),
+                        global: upgrade.global,
+                        installed_version: upgrade.installed_version.clone(),
+                        latest_version: upgrade.latest_version.clone(),
+                    })
+                    .collect(),
+                hold: sorted_scoped_apps(&to_install.scoop_apps_to_hold),
+                unhold: sorted_scoped_apps(&to_install.scoop_apps_to_unhold),
+            },
+            uninstall: UninstallReport {
+                buckets: sorted_buckets(&to_uninstall.scoop_buckets),
+                apps: sorted_scoped_apps(&to_uninstall.scoop_apps),
+                kept_buckets: sorted_buckets(&to_uninstall.kept_scoop_buckets),
+                kept_apps: sorted_scoped_apps(&to_uninstall.kept_scoop_apps),
+            },
+            dependencies: required
+                .scoop_apps
+                .iter()
+                .map(|(app, deps)| (app.to_string(), sorted_apps(deps)))
+                .collect(),
+            unresolved: required
+                .unresolved
+                .iter()
+                .sorted_by_key(|failure| failure.app.to_string())
+                .map(|failure| UnresolvedApp {
+                    app: failure.app.clone(),
+                    optional: failure.optional,
+                    error: failure.error.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .into_diagnostic()
+            .wrap_err("failed to serialize plan as JSON")
+    }
+
+    pub fn to_yaml(&self) -> Result<String> {
+        serde_yaml::to_string(self)
+            .into_diagnostic()
+            .wrap_err("failed to serialize plan as YAML")
+    }
+}
+
+fn sorted_buckets<'a>(buckets: impl IntoIterator<Item = &'a ScoopBucket>) -> Vec<ScoopBucket> {
+    buckets
+        .into_iter()
+        .cloned()
+        .sorted_by(|a, b| a.name.cmp(&b.name))
+        .collect()
+}
+
+fn sorted_scoped_apps<'a>(apps: impl IntoIterator<Item = &'a AppSpec>) -> Vec<ScopedApp> {
+    apps.into_iter()
+        .sorted_by_key(|spec| (spec.app.to_string(), spec.global))
+        .map(|spec| ScopedApp {
+            app: spec.app.clone(),
+            global: spec.global,
+        })
+        .collect()
+}
+
+fn sorted_apps<'a>(apps: impl IntoIterator<Item = &'a ScoopApp>) -> Vec<ScoopApp> {
+    apps.into_iter()
+        .cloned()
+        .sorted_by_key(|app| app.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::{app, config, required};
+
+    #[test]
+    fn serializes_plan_as_json() {
+        let config = config(
+            "
+scoop_buckets: [{ name: main, source: https://example.com/main }]
+scoop_apps: [main/a]
+",
+        );
+        let required = required(
+            &config,
+            &[("main/a", &["main/a", "main/b"]), ("main/b", &["main/b"])],
+        );
+        let to_uninstall = ThingsToUninstall {
+            scoop_buckets: HashSet::new(),
+            scoop_apps: HashSet::from([AppSpec::unpinned(app("main/old"))]),
+            kept_scoop_buckets: HashSet::from([ScoopBucket {
+                name: "private".to_string(),
+                source: "https://example.com/private".to_string(),
+            }]),
+            kept_scoop_apps: HashSet::new(),
+        };
+        let to_install = ThingsToInstall {
+            scoop_buckets: HashSet::new(),
+            scoop_apps: HashSet::from([
+                AppSpec::unpinned(app("main/b")),
+                AppSpec::unpinned(app("main/a")),
+            ]),
+            scoop_apps_to_reinstall: HashSet::new(),
+            scoop_apps_to_upgrade: HashSet::new(),
+            scoop_apps_to_hold: HashSet::new(),
+            scoop_apps_to_unhold: HashSet::new(),
+        };
+
+        let report = PlanReport::new(&config, &required, &to_uninstall, &to_install);
+        let app = |id: &str, explicit: bool, required_by: &[&str]| {
+            json!({
+                "app": id,
+                "version": null,
+                "arch": null,
+                "global": false,
+                "hold": false,
+                "explicit": explicit,
+                "required_by": required_by,
+            })
+        };
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&report.to_json().unwrap()).unwrap(),
+            json!({
+                "install": {
+                    "buckets": [],
+                    "apps": [app("main/a", true, &[]), app("main/b", false, &["main/a"])],
+                    "reinstall": [],
+                    "upgrade": [],
+                    "hold": [],
+                    "unhold": [],
+                },
+                "uninstall": {
+                    "buckets": [],
+                    "apps": [{ "app": "main/old", "global": false }],
+                    "kept_buckets": [{ "name": "private", "source": "https://example.com/private" }],
+                    "kept_apps": [],
+                },
+                "dependencies": {
+                    "main/a": ["main/a", "main/b"],
+                    "main/b": ["main/b"],
+                },
+                "unresolved": [],
+            })
+        );
+    }
+}
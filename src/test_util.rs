@@ -0,0 +1,78 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, process};
+
+use crate::RequiredThings;
+use crate::config::{Config, ScoopApp};
+
+pub fn app(id: &str) -> ScoopApp {
+    id.parse().unwrap()
+}
+
+pub fn config(yaml: &str) -> Config {
+    serde_yaml::from_str(yaml).unwrap()
+}
+
+/// 設定ファイルに記載されたアプリケーションと、その (間接的なものも含む) 依存関係から解決結果を
+/// 作ります。global なアプリケーションの依存関係は、全ユーザー向けに必要なものとする。
+pub fn required(config: &Config, resolved: &[(&str, &[&str])]) -> RequiredThings {
+    let scoop_apps: HashMap<_, HashSet<_>> = resolved
+        .iter()
+        .map(|(id, deps)| (app(id), deps.iter().map(|dep| app(dep)).collect()))
+        .collect();
+    let app_specs: HashMap<_, _> = config
+        .scoop_apps
+        .iter()
+        .map(|spec| (spec.app.clone(), spec.clone()))
+        .collect();
+    let (global_apps, user_apps) = scoop_apps.keys().cloned().partition(|app| {
+        app_specs
+            .values()
+            .any(|spec| spec.global && (spec.app == *app || scoop_apps[&spec.app].contains(app)))
+    });
+
+    RequiredThings {
+        scoop_buckets: config.scoop_buckets.clone(),
+        scoop_apps,
+        app_specs,
+        default_arch: None,
+        user_apps,
+        global_apps,
+        unresolved: Vec::new(),
+        keep_unresolved: true,
+    }
+}
+
+/// テストごとに作り、終わったら削除する一時ディレクトリ。
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "declarative-scoop-test-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// 親ディレクトリを作ってからファイルを書き込みます。
+pub fn write(path: &Path, content: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+}